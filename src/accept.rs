@@ -0,0 +1,238 @@
+use axum::http::{header::ACCEPT, HeaderMap, HeaderValue};
+
+use crate::media_type::{split_unquoted, MediaType};
+
+const MAX_QUALITY: u16 = 1000;
+
+#[derive(Clone, Debug)]
+pub(crate) struct MediaRange {
+    media_type: MediaType,
+    quality: u16,
+}
+
+impl MediaRange {
+    fn parse(value: &str) -> Option<Self> {
+        let mut media_type = MediaType::parse(value)?;
+
+        if media_type.type_() == "*" && media_type.subtype() != "*" {
+            return None;
+        }
+
+        // Parameters from `q` onwards are accept-extensions rather than media type parameters.
+        let quality = match media_type.split_off_params("q").first() {
+            Some((_, quality)) => parse_quality(quality)?,
+            None => MAX_QUALITY,
+        };
+
+        Some(Self { media_type, quality })
+    }
+
+    fn specificity(&self, media_type: &MediaType) -> Option<usize> {
+        let range = &self.media_type;
+
+        if range.type_() == "*" {
+            return Some(0);
+        }
+
+        if range.type_() != media_type.type_() {
+            return None;
+        }
+
+        if range.subtype() == "*" {
+            return Some(1);
+        }
+
+        if range.subtype() != media_type.subtype() {
+            return None;
+        }
+
+        // Parameters only rule a match out when they conflict, so `application/json; charset=utf-8`
+        // still selects plain `application/json`.
+        let params_match = range
+            .params()
+            .all(|(name, value)| media_type.param(name).is_none_or(|other| other.eq_ignore_ascii_case(value)));
+
+        params_match.then(|| 2 + range.params().count())
+    }
+}
+
+pub(crate) fn parse(accept: &str) -> Vec<MediaRange> {
+    split_unquoted(accept, ',')
+        .into_iter()
+        .filter(|range| !range.trim().is_empty())
+        .filter_map(MediaRange::parse)
+        .collect()
+}
+
+pub(crate) fn accept_header(headers: &HeaderMap) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .filter(|header_value| !header_value.trim().is_empty())
+        .collect();
+
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(","))
+    }
+}
+
+// Copies the `Accept` header without the ranges for `essence`, leaving it out entirely when nothing
+// else was listed.
+pub(crate) fn without(headers: &HeaderMap, essence: &str) -> HeaderMap {
+    let mut filtered = HeaderMap::new();
+    let accept = accept_header(headers).unwrap_or_default();
+
+    let ranges: Vec<&str> = split_unquoted(&accept, ',')
+        .into_iter()
+        .filter(|range| MediaType::parse(range).is_some_and(|media_type| !media_type.essence().eq_ignore_ascii_case(essence)))
+        .collect();
+
+    if !ranges.is_empty() {
+        if let Ok(accept) = HeaderValue::from_str(&ranges.join(",")) {
+            filtered.insert(ACCEPT, accept);
+        }
+    }
+
+    filtered
+}
+
+// Picks the index of the preferred group of equivalent media types, treating a missing header as
+// accepting anything.
+pub(crate) fn negotiate_groups(headers: &HeaderMap, groups: &[&[&str]]) -> Option<usize> {
+    let Some(accept) = accept_header(headers) else {
+        return (!groups.is_empty()).then_some(0);
+    };
+
+    let (indices, media_types): (Vec<_>, Vec<_>) = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(index, group)| group.iter().map(move |media_type| (index, *media_type)))
+        .unzip();
+
+    negotiate(&accept, &media_types).map(|index| indices[index])
+}
+
+// Picks the index of the preferred entry of `available` following RFC 9110 section 12.5.1: each
+// candidate takes the quality of the most specific range matching it, the highest quality wins,
+// and ties go to the more specific match and then to the earlier candidate.
+pub(crate) fn negotiate(accept: &str, available: &[&str]) -> Option<usize> {
+    let ranges = parse(accept);
+
+    available
+        .iter()
+        .enumerate()
+        .filter_map(|(index, candidate)| {
+            let (specificity, quality) = quality(&ranges, &MediaType::parse(candidate)?)?;
+
+            (quality > 0).then_some((index, quality, specificity))
+        })
+        .fold(None, |best: Option<(usize, u16, usize)>, current| match best {
+            Some(best) if (best.1, best.2) >= (current.1, current.2) => Some(best),
+            _ => Some(current),
+        })
+        .map(|(index, _, _)| index)
+}
+
+// Whether the most specific range matching `media_type` gives it `q=0`.
+pub(crate) fn refuses(headers: &HeaderMap, media_type: &str) -> bool {
+    let (Some(accept), Some(media_type)) = (accept_header(headers), MediaType::parse(media_type)) else {
+        return false;
+    };
+
+    quality(&parse(&accept), &media_type).is_some_and(|(_, quality)| quality == 0)
+}
+
+// The specificity and quality of the most specific range matching `candidate`.
+fn quality(ranges: &[MediaRange], candidate: &MediaType) -> Option<(usize, u16)> {
+    ranges
+        .iter()
+        .filter_map(|range| range.specificity(candidate).map(|specificity| (specificity, range.quality)))
+        .fold(None, |best: Option<(usize, u16)>, current| match best {
+            Some(best) if best.0 >= current.0 => Some(best),
+            _ => Some(current),
+        })
+}
+
+pub(crate) fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+
+    match whole {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(MAX_QUALITY),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = "application/json";
+    const PROTOBUF: &str = "application/x-protobuf";
+
+    #[test]
+    fn parses_quality_values() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.125"), Some(125));
+
+        for invalid in ["", ".5", "1.5", "2", "0.1234", "0.x", "-0", "0,5"] {
+            assert_eq!(parse_quality(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn prefers_the_highest_quality() {
+        assert_eq!(negotiate("application/json;q=0.5, application/x-protobuf", &[JSON, PROTOBUF]), Some(1));
+        assert_eq!(negotiate("application/json;q=0.9, application/x-protobuf;q=0.8", &[JSON, PROTOBUF]), Some(0));
+        assert_eq!(negotiate("application/json;q=0", &[JSON, PROTOBUF]), None);
+    }
+
+    #[test]
+    fn ignores_invalid_ranges() {
+        assert_eq!(negotiate("application/json;q=2, application/x-protobuf;q=0.1", &[JSON, PROTOBUF]), Some(1));
+        assert_eq!(negotiate("*/json", &[JSON]), None);
+        assert_eq!(negotiate("json, , application/x-protobuf", &[JSON, PROTOBUF]), Some(1));
+    }
+
+    #[test]
+    fn most_specific_range_sets_the_quality() {
+        assert_eq!(negotiate("application/json;q=0, */*", &[JSON, PROTOBUF]), Some(1));
+        assert_eq!(negotiate("*/*;q=0.1, application/*;q=0.5, application/json", &[PROTOBUF, JSON]), Some(1));
+        assert_eq!(negotiate("*/*, application/*;q=0", &[JSON, "text/plain"]), Some(1));
+    }
+
+    #[test]
+    fn breaks_ties_by_specificity_then_order() {
+        assert_eq!(negotiate("application/*, application/x-protobuf", &[JSON, PROTOBUF]), Some(1));
+        assert_eq!(negotiate("*/*", &[JSON, PROTOBUF]), Some(0));
+        assert_eq!(negotiate("*/*", &[PROTOBUF, JSON]), Some(0));
+    }
+
+    #[test]
+    fn conflicting_parameters_rule_a_range_out() {
+        let available = ["application/json; charset=utf-8", PROTOBUF];
+
+        assert_eq!(negotiate("application/json;charset=utf-16, application/x-protobuf;q=0.5", &available), Some(1));
+        assert_eq!(negotiate("application/json;charset=UTF-8, application/x-protobuf;q=0.5", &available), Some(0));
+        assert_eq!(negotiate("application/json;charset=utf-8", &[JSON]), Some(0));
+        assert_eq!(negotiate("application/json;q=0, application/json;charset=utf-8", &available[..1]), Some(0));
+    }
+
+    #[test]
+    fn missing_header_accepts_the_first_group() {
+        assert_eq!(negotiate_groups(&HeaderMap::new(), &[&[JSON], &[PROTOBUF]]), Some(0));
+        assert_eq!(negotiate_groups(&HeaderMap::new(), &[]), None);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{error::Error, io};
+
+use axum::{body::Bytes, extract::{FromRequest, Request}};
+use http_body_util::LengthLimitError;
+
+use crate::{compression::{self, DEFAULT_DECOMPRESSED_LIMIT}, Format, JsonOrProtobufConfig, JsonOrProtobufRejection, MediaType};
+
+/// Maximum request body sizes, per format, for the extractors in this crate.
+///
+/// Insert it with an [`Extension`](axum::Extension) layer on a router, or on a single route to
+/// override the router's limits. When present it replaces axum's `DefaultBodyLimit`, and the same
+/// limit caps the body once decompressed.
+#[derive(Clone, Debug)]
+pub struct BodyLimits {
+    default: usize,
+    formats: Vec<(Format, usize)>,
+}
+
+impl BodyLimits {
+    /// Limits every format to `default` bytes.
+    pub fn new(default: usize) -> Self {
+        Self {
+            default,
+            formats: Vec::new(),
+        }
+    }
+
+    /// Limits bodies in `format` to `limit` bytes.
+    pub fn format(mut self, format: Format, limit: usize) -> Self {
+        self.formats.retain(|(existing, _)| *existing != format);
+        self.formats.push((format, limit));
+        self
+    }
+
+    /// The limit for bodies in `format`, where `None` stands for a custom codec.
+    pub fn limit_for(&self, format: Option<Format>) -> usize {
+        self.formats
+            .iter()
+            .find(|(existing, _)| Some(*existing) == format)
+            .map_or(self.default, |(_, limit)| *limit)
+    }
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self::new(DEFAULT_DECOMPRESSED_LIMIT)
+    }
+}
+
+// Compressed bodies of at least this many bytes are decompressed with `spawn_blocking`.
+const BLOCKING_DECOMPRESSION_THRESHOLD: usize = 64 * 1024;
+
+// A route's own limits take precedence over those of the configuration.
+pub(crate) fn body_limit(request: &Request, format: Option<Format>) -> Option<usize> {
+    request
+        .extensions()
+        .get::<BodyLimits>()
+        .or_else(|| request.extensions().get::<JsonOrProtobufConfig>()?.limits.as_ref())
+        .map(|limits| limits.limit_for(format))
+}
+
+// Buffers the request body, undoing any `Content-Encoding`. Unsupported encodings are refused
+// before the body is read.
+pub(crate) async fn read_body<S>(request: Request, state: &S, media_type: &MediaType) -> Result<Bytes, JsonOrProtobufRejection>
+where
+    S: Send + Sync
+{
+    let encodings = compression::content_encodings(request.headers())?;
+    let limit = body_limit(&request, Format::from_media_type(media_type));
+
+    let bytes = match limit {
+        Some(limit) => axum::body::to_bytes(request.into_body(), limit)
+            .await
+            .map_err(|err| {
+                if err.source().is_some_and(|source| source.is::<LengthLimitError>()) {
+                    JsonOrProtobufRejection::BodyTooLarge { limit }
+                } else {
+                    JsonOrProtobufRejection::BodyReadError(err)
+                }
+            })?,
+        None => Bytes::from_request(request, state).await?,
+    };
+
+    let limit = limit.unwrap_or(DEFAULT_DECOMPRESSED_LIMIT);
+
+    // Larger bodies are decompressed on the blocking pool, so that they don't hold up other tasks
+    // on the executor.
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) if !encodings.is_empty() && bytes.len() >= BLOCKING_DECOMPRESSION_THRESHOLD => runtime
+            .spawn_blocking(move || compression::decompress(bytes, &encodings, limit))
+            .await
+            .map_err(|err| JsonOrProtobufRejection::DecompressionError(io::Error::other(err)))?,
+        _ => compression::decompress(bytes, &encodings, limit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn limited(body: &'static str, limit: usize) -> Request {
+        let mut request = Request::new(Body::from(body));
+        request.extensions_mut().insert(BodyLimits::new(limit));
+        request
+    }
+
+    #[test]
+    fn route_limits_beat_the_configuration() {
+        let mut request = Request::new(Body::empty());
+
+        assert_eq!(body_limit(&request, Some(Format::Json)), None);
+
+        request.extensions_mut().insert(JsonOrProtobufConfig::new().limits(BodyLimits::new(8).format(Format::Protobuf, 16)));
+
+        assert_eq!(body_limit(&request, Some(Format::Json)), Some(8));
+        assert_eq!(body_limit(&request, Some(Format::Protobuf)), Some(16));
+
+        request.extensions_mut().insert(BodyLimits::new(4));
+
+        assert_eq!(body_limit(&request, Some(Format::Json)), Some(4));
+        assert_eq!(body_limit(&request, Some(Format::Protobuf)), Some(4));
+        assert_eq!(body_limit(&request, None), Some(4));
+    }
+
+    #[test]
+    fn refuses_bodies_over_the_limit_with_413() {
+        let media_type = MediaType::parse("application/json").unwrap();
+
+        let rejection = read_body(limited("{\"name\":\"x\"}", 4), &(), &media_type).now_or_never().unwrap().unwrap_err();
+
+        assert!(matches!(rejection, JsonOrProtobufRejection::BodyTooLarge { limit: 4 }));
+        assert_eq!(rejection.status(), axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+
+        assert_eq!(read_body(limited("{}", 4), &(), &media_type).now_or_never().unwrap().unwrap(), "{}");
+    }
+}
@@ -0,0 +1,726 @@
+use std::{borrow::Cow, marker::PhantomData};
+
+use axum::{async_trait, body::Bytes, extract::{FromRequest, Request}, http::{header::{CONTENT_TYPE, VARY}, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}, Json};
+use prost::{Message, Name};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{accept, body, protobuf_content_type_for, ContentTypeError, Format, JsonOrProtobuf, JsonOrProtobufConfig, JsonOrProtobufRejection, MediaType, NotAcceptable, ProblemRejection, SplitJsonOrProtobuf};
+
+/// A wire format that [`Negotiated`] can read and write.
+pub trait Codec {
+    /// Every media type recognised as this format.
+    fn media_types() -> &'static [&'static str];
+
+    /// The `Content-Type` of responses in this format.
+    fn content_type() -> &'static str {
+        Self::media_types()[0]
+    }
+
+    /// Whether a body in this format can be read with the given `charset` parameter.
+    fn supports_charset(_charset: &str) -> bool {
+        true
+    }
+}
+
+pub trait Decode<T>: Codec {
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection>;
+
+    /// Whether a `T` can be read from a body of this media type, for codecs that check parameters
+    /// beyond the charset. Other bodies are refused with `415`.
+    fn accepts(_media_type: &MediaType) -> bool {
+        true
+    }
+}
+
+pub trait Encode<T>: Codec {
+    fn encode(value: &T) -> Result<Bytes, axum::Error>;
+
+    /// The `Content-Type` of an encoded `T`, for codecs that name the type in a parameter.
+    fn encoded_content_type() -> Cow<'static, str> {
+        Cow::Borrowed(Self::content_type())
+    }
+}
+
+/// A response body that is encoded up front, so wrappers such as [`Compressed`](crate::Compressed)
+/// can work on the encoded bytes.
+pub trait EncodeBody {
+    /// The `Content-Type` of the body along with the encoded body itself.
+    fn encode_body(&self) -> (Cow<'static, str>, Result<Bytes, axum::Error>);
+
+    /// Whether the format was negotiated from the `Accept` header, so that responses need
+    /// `Vary: Accept`.
+    fn negotiated(&self) -> bool {
+        false
+    }
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn media_types() -> &'static [&'static str] {
+        Format::Json.media_types()
+    }
+
+    fn supports_charset(charset: &str) -> bool {
+        Format::Json.supports_charset(charset)
+    }
+}
+
+impl<T> Decode<T> for JsonCodec
+where
+    T: DeserializeOwned
+{
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        let Json(value) = Json::from_bytes(&bytes)?;
+
+        Ok(value)
+    }
+}
+
+impl<T> Encode<T> for JsonCodec
+where
+    T: Serialize
+{
+    fn encode(value: &T) -> Result<Bytes, axum::Error> {
+        serde_json::to_vec(value)
+            .map(Bytes::from)
+            .map_err(axum::Error::new)
+    }
+}
+
+pub struct ProtobufCodec;
+
+impl Codec for ProtobufCodec {
+    fn media_types() -> &'static [&'static str] {
+        Format::Protobuf.media_types()
+    }
+
+    fn content_type() -> &'static str {
+        Format::Protobuf.content_type()
+    }
+}
+
+impl<T> Decode<T> for ProtobufCodec
+where
+    T: Message + Default
+{
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        Ok(T::decode(bytes)?)
+    }
+}
+
+impl<T> Encode<T> for ProtobufCodec
+where
+    T: Message
+{
+    fn encode(value: &T) -> Result<Bytes, axum::Error> {
+        Ok(value.encode_to_vec().into())
+    }
+}
+
+/// Like [`ProtobufCodec`], for messages implementing [`Name`]. Bodies whose `messageType` or
+/// `proto` parameter names another message are refused, and responses name the message in
+/// `messageType`.
+pub struct NamedProtobufCodec;
+
+impl Codec for NamedProtobufCodec {
+    fn media_types() -> &'static [&'static str] {
+        ProtobufCodec::media_types()
+    }
+
+    fn content_type() -> &'static str {
+        ProtobufCodec::content_type()
+    }
+}
+
+impl<T> Decode<T> for NamedProtobufCodec
+where
+    T: Message + Default + Name
+{
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        ProtobufCodec::decode(bytes)
+    }
+
+    fn accepts(media_type: &MediaType) -> bool {
+        media_type
+            .message_type()
+            .is_none_or(|message_type| message_type.trim_start_matches('.') == T::full_name())
+    }
+}
+
+impl<T> Encode<T> for NamedProtobufCodec
+where
+    T: Message + Name
+{
+    fn encode(value: &T) -> Result<Bytes, axum::Error> {
+        ProtobufCodec::encode(value)
+    }
+
+    fn encoded_content_type() -> Cow<'static, str> {
+        Cow::Owned(protobuf_content_type_for::<T>())
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPackCodec {
+    fn media_types() -> &'static [&'static str] {
+        Format::MsgPack.media_types()
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<T> Decode<T> for MsgPackCodec
+where
+    T: DeserializeOwned
+{
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        Ok(rmp_serde::from_slice(&bytes)?)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<T> Encode<T> for MsgPackCodec
+where
+    T: Serialize
+{
+    // Structs are written as maps keyed by field name, matching their JSON shape.
+    fn encode(value: &T) -> Result<Bytes, axum::Error> {
+        rmp_serde::to_vec_named(value)
+            .map(Bytes::from)
+            .map_err(axum::Error::new)
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+    fn media_types() -> &'static [&'static str] {
+        Format::Cbor.media_types()
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T> Decode<T> for CborCodec
+where
+    T: DeserializeOwned
+{
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        Ok(ciborium::from_reader(bytes.as_ref())?)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T> Encode<T> for CborCodec
+where
+    T: Serialize
+{
+    fn encode(value: &T) -> Result<Bytes, axum::Error> {
+        let mut buf = Vec::new();
+
+        ciborium::into_writer(value, &mut buf)
+            .map(|_| Bytes::from(buf))
+            .map_err(axum::Error::new)
+    }
+}
+
+#[cfg(feature = "grpc-web")]
+pub struct GrpcWebCodec;
+
+#[cfg(feature = "grpc-web")]
+impl Codec for GrpcWebCodec {
+    fn media_types() -> &'static [&'static str] {
+        Format::GrpcWeb.media_types()
+    }
+}
+
+#[cfg(feature = "grpc-web")]
+impl<T> Decode<T> for GrpcWebCodec
+where
+    T: Message + Default
+{
+    fn decode(bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        Ok(T::decode(crate::grpc_web::unframe(bytes)?)?)
+    }
+}
+
+#[cfg(feature = "grpc-web")]
+impl<T> Encode<T> for GrpcWebCodec
+where
+    T: Message
+{
+    fn encode(value: &T) -> Result<Bytes, axum::Error> {
+        Ok(crate::grpc_web::frame(&value.encode_to_vec()))
+    }
+}
+
+/// A set of codecs to negotiate between, either a single [`Codec`] or a tuple of them listed in
+/// order of preference.
+pub trait Codecs {
+    /// The media types of each codec in the set, in order.
+    fn codec_media_types() -> Vec<&'static [&'static str]>;
+
+    fn codec_content_type(index: usize) -> &'static str;
+
+    fn codec_supports_charset(index: usize, charset: &str) -> bool;
+}
+
+pub trait DecodeWith<T>: Codecs {
+    fn decode_with(index: usize, bytes: Bytes) -> Result<T, JsonOrProtobufRejection>;
+
+    fn accepts_with(_index: usize, _media_type: &MediaType) -> bool {
+        true
+    }
+}
+
+pub trait EncodeWith<T>: Codecs {
+    fn encode_with(index: usize, value: &T) -> Result<Bytes, axum::Error>;
+
+    fn encoded_content_type_with(index: usize) -> Cow<'static, str> {
+        Cow::Borrowed(Self::codec_content_type(index))
+    }
+}
+
+impl<C> Codecs for C
+where
+    C: Codec
+{
+    fn codec_media_types() -> Vec<&'static [&'static str]> {
+        vec![C::media_types()]
+    }
+
+    fn codec_content_type(_: usize) -> &'static str {
+        C::content_type()
+    }
+
+    fn codec_supports_charset(_: usize, charset: &str) -> bool {
+        C::supports_charset(charset)
+    }
+}
+
+impl<C, T> DecodeWith<T> for C
+where
+    C: Decode<T>
+{
+    fn decode_with(_: usize, bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+        C::decode(bytes)
+    }
+
+    fn accepts_with(_: usize, media_type: &MediaType) -> bool {
+        C::accepts(media_type)
+    }
+}
+
+impl<C, T> EncodeWith<T> for C
+where
+    C: Encode<T>
+{
+    fn encode_with(_: usize, value: &T) -> Result<Bytes, axum::Error> {
+        C::encode(value)
+    }
+
+    fn encoded_content_type_with(_: usize) -> Cow<'static, str> {
+        C::encoded_content_type()
+    }
+}
+
+macro_rules! impl_codecs {
+    ($($codec:ident => $index:tt),+) => {
+        impl<$($codec),+> Codecs for ($($codec,)+)
+        where
+            $($codec: Codec),+
+        {
+            fn codec_media_types() -> Vec<&'static [&'static str]> {
+                vec![$($codec::media_types()),+]
+            }
+
+            fn codec_content_type(index: usize) -> &'static str {
+                match index {
+                    $($index => $codec::content_type(),)+
+                    _ => unreachable!("codec index out of range"),
+                }
+            }
+
+            fn codec_supports_charset(index: usize, charset: &str) -> bool {
+                match index {
+                    $($index => $codec::supports_charset(charset),)+
+                    _ => unreachable!("codec index out of range"),
+                }
+            }
+        }
+
+        impl<T, $($codec),+> DecodeWith<T> for ($($codec,)+)
+        where
+            $($codec: Decode<T>),+
+        {
+            fn decode_with(index: usize, bytes: Bytes) -> Result<T, JsonOrProtobufRejection> {
+                match index {
+                    $($index => $codec::decode(bytes),)+
+                    _ => unreachable!("codec index out of range"),
+                }
+            }
+
+            fn accepts_with(index: usize, media_type: &MediaType) -> bool {
+                match index {
+                    $($index => $codec::accepts(media_type),)+
+                    _ => unreachable!("codec index out of range"),
+                }
+            }
+        }
+
+        impl<T, $($codec),+> EncodeWith<T> for ($($codec,)+)
+        where
+            $($codec: Encode<T>),+
+        {
+            fn encode_with(index: usize, value: &T) -> Result<Bytes, axum::Error> {
+                match index {
+                    $($index => $codec::encode(value),)+
+                    _ => unreachable!("codec index out of range"),
+                }
+            }
+
+            fn encoded_content_type_with(index: usize) -> Cow<'static, str> {
+                match index {
+                    $($index => $codec::encoded_content_type(),)+
+                    _ => unreachable!("codec index out of range"),
+                }
+            }
+        }
+    };
+}
+
+impl_codecs!(C1 => 0, C2 => 1);
+impl_codecs!(C1 => 0, C2 => 1, C3 => 2);
+impl_codecs!(C1 => 0, C2 => 1, C3 => 2, C4 => 3);
+impl_codecs!(C1 => 0, C2 => 1, C3 => 2, C4 => 3, C5 => 4);
+impl_codecs!(C1 => 0, C2 => 1, C3 => 2, C4 => 3, C5 => 4, C6 => 5);
+
+pub type DefaultCodecs = (JsonCodec, ProtobufCodec);
+
+/// [`Negotiated`] for types that only implement serde. Protobuf bodies are refused with `415`, and
+/// [`Negotiated::try_from_accept_header`] refuses clients that only accept protobuf with `406`.
+pub type JsonOnly<T> = Negotiated<T, JsonCodec>;
+
+/// [`Negotiated`] for types that only implement [`Message`], the protobuf counterpart of
+/// [`JsonOnly`].
+pub type ProtobufOnly<T> = Negotiated<T, ProtobufCodec>;
+
+// The codecs behind `JsonOrProtobuf`, one per entry of `Format::ALL`, so the enum is read the same
+// way as `Negotiated`.
+pub(crate) struct FormatCodecs;
+
+impl Codecs for FormatCodecs {
+    fn codec_media_types() -> Vec<&'static [&'static str]> {
+        Format::ALL.iter().map(|format| format.media_types()).collect()
+    }
+
+    fn codec_content_type(index: usize) -> &'static str {
+        Format::ALL[index].content_type()
+    }
+
+    fn codec_supports_charset(index: usize, charset: &str) -> bool {
+        Format::ALL[index].supports_charset(charset)
+    }
+}
+
+impl<J, P> DecodeWith<SplitJsonOrProtobuf<J, P>> for FormatCodecs
+where
+    J: DeserializeOwned,
+    P: Message + Default
+{
+    fn decode_with(index: usize, bytes: Bytes) -> Result<SplitJsonOrProtobuf<J, P>, JsonOrProtobufRejection> {
+        match Format::ALL[index] {
+            Format::Protobuf => Ok(SplitJsonOrProtobuf::Protobuf(ProtobufCodec::decode(bytes)?)),
+            Format::Json => Ok(SplitJsonOrProtobuf::Json(JsonCodec::decode(bytes)?)),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => Ok(SplitJsonOrProtobuf::MsgPack(MsgPackCodec::decode(bytes)?)),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Ok(SplitJsonOrProtobuf::Cbor(CborCodec::decode(bytes)?)),
+            #[cfg(feature = "grpc-web")]
+            Format::GrpcWeb => Ok(SplitJsonOrProtobuf::GrpcWeb(GrpcWebCodec::decode(bytes)?)),
+        }
+    }
+}
+
+/// Like [`JsonOrProtobuf`], but generic over the set of [`Codecs`] to negotiate between.
+pub struct Negotiated<T, C = DefaultCodecs> {
+    body: T,
+    codec: usize,
+    negotiated: bool,
+    codecs: PhantomData<fn() -> C>,
+}
+
+impl<T, C> Negotiated<T, C>
+where
+    C: Codecs
+{
+    fn with_codec(body: T, codec: usize) -> Self {
+        Self {
+            body,
+            codec,
+            negotiated: false,
+            codecs: PhantomData,
+        }
+    }
+
+    fn with_negotiated_codec(body: T, codec: usize) -> Self {
+        Self {
+            negotiated: true,
+            ..Self::with_codec(body, codec)
+        }
+    }
+
+    pub fn new(body: T, content_type: &str) -> Result<Self, ContentTypeError> {
+        let media_type: MediaType = content_type.parse()?;
+
+        match codec_index::<C>(&media_type) {
+            Some(codec) => Ok(Self::with_codec(body, codec)),
+            None => Err(ContentTypeError(content_type.to_string()))
+        }
+    }
+
+    /// Picks the response codec from the `Accept` header, falling back to the first codec when
+    /// none is acceptable.
+    pub fn from_accept_header(body: T, headers: &HeaderMap) -> Self {
+        let codec = accept::negotiate_groups(headers, &C::codec_media_types()).unwrap_or(0);
+
+        Self::with_negotiated_codec(body, codec)
+    }
+
+    pub fn try_from_accept_header(body: T, headers: &HeaderMap) -> Result<Self, NotAcceptable> {
+        let media_types = C::codec_media_types();
+
+        match accept::negotiate_groups(headers, &media_types) {
+            Some(codec) => Ok(Self::with_negotiated_codec(body, codec)),
+            None => Err(NotAcceptable::new(media_types.concat())),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        C::codec_content_type(self.codec)
+    }
+
+    pub fn body(&self) -> &T {
+        &self.body
+    }
+
+    pub fn into_inner(self) -> T {
+        self.body
+    }
+
+    pub fn decompose(self) -> (T, String) {
+        let content_type = self.content_type().to_string();
+
+        (self.body, content_type)
+    }
+}
+
+fn codec_index<C: Codecs>(media_type: &MediaType) -> Option<usize> {
+    C::codec_media_types()
+        .iter()
+        .position(|media_types| media_types.contains(&media_type.essence()))
+}
+
+#[async_trait]
+impl<T, C, S> FromRequest<S> for Negotiated<T, C>
+where
+    C: DecodeWith<T>,
+    S: Send + Sync
+{
+    type Rejection = ProblemRejection;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let config = JsonOrProtobufConfig::of(request.extensions());
+        let reject = ProblemRejection::negotiate(request.headers(), config.default_format);
+
+        Self::extract(request, state, &config)
+            .await
+            .map_err(reject)
+    }
+}
+
+impl<T, C> Negotiated<T, C>
+where
+    C: DecodeWith<T>
+{
+    async fn extract<S>(request: Request, state: &S, config: &JsonOrProtobufConfig) -> Result<Self, JsonOrProtobufRejection>
+    where
+        S: Send + Sync
+    {
+        let expected = || {
+            C::codec_media_types()
+                .concat()
+                .into_iter()
+                .filter(|media_type| config.accepts_media_type(media_type))
+                .collect()
+        };
+
+        let media_type = match MediaType::from_request_headers(request.headers(), expected) {
+            Err(JsonOrProtobufRejection::MissingContentType { .. }) if !config.strict => config.default_format
+                .content_type()
+                .parse()?,
+            media_type => media_type?,
+        };
+
+        let accepted = config.accepts_media_type(media_type.essence());
+
+        let Some(codec) = codec_index::<C>(&media_type).filter(|codec| accepted && C::accepts_with(*codec, &media_type)) else {
+            return Err(JsonOrProtobufRejection::UnsupportedMediaType { media_type, expected: expected() });
+        };
+
+        if let Some(charset) = media_type.charset() {
+            if !C::codec_supports_charset(codec, charset) {
+                return Err(JsonOrProtobufRejection::UnsupportedCharset(media_type));
+            }
+        }
+
+        let bytes = body::read_body(request, state, &media_type).await?;
+
+        Ok(Self::with_codec(C::decode_with(codec, bytes)?, codec))
+    }
+}
+
+impl<T, C> EncodeBody for Negotiated<T, C>
+where
+    C: EncodeWith<T>
+{
+    fn encode_body(&self) -> (Cow<'static, str>, Result<Bytes, axum::Error>) {
+        (C::encoded_content_type_with(self.codec), C::encode_with(self.codec, &self.body))
+    }
+
+    fn negotiated(&self) -> bool {
+        self.negotiated
+    }
+}
+
+impl<T, C> IntoResponse for Negotiated<T, C>
+where
+    C: EncodeWith<T>
+{
+    fn into_response(self) -> Response {
+        body_response(&self)
+    }
+}
+
+pub(crate) fn body_response<B: EncodeBody>(body: &B) -> Response {
+    let (content_type, encoded) = body.encode_body();
+    let mut response = encoded_response(&content_type, encoded);
+
+    if body.negotiated() && response.status().is_success() {
+        vary_accept(&mut response);
+    }
+
+    response
+}
+
+pub(crate) fn vary_accept(response: &mut Response) {
+    let varies = response
+        .headers()
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept"));
+
+    if !varies {
+        response.headers_mut().append(VARY, HeaderValue::from_static("accept"));
+    }
+}
+
+// Successful responses carry their `Format` as an extension, when it is one of the built-in formats,
+// so middleware can tell how the body was encoded.
+pub(crate) fn encoded_response(content_type: &str, encoded: Result<Bytes, axum::Error>) -> Response {
+    let bytes = match encoded {
+        Ok(bytes) => bytes,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+
+    let mut response = ([(CONTENT_TYPE, content_type)], bytes).into_response();
+
+    let format = MediaType::parse(content_type).and_then(|media_type| Format::from_media_type(&media_type));
+
+    if let Some(format) = format {
+        response.extensions_mut().insert(format);
+    }
+
+    response
+}
+
+impl<T, C> TryFrom<JsonOrProtobuf<T>> for Negotiated<T, C>
+where
+    C: Codecs
+{
+    type Error = ContentTypeError;
+
+    fn try_from(value: JsonOrProtobuf<T>) -> Result<Self, Self::Error> {
+        let (body, content_type) = value.decompose();
+
+        Self::new(body, &content_type)
+    }
+}
+
+impl<T, C> TryFrom<Negotiated<T, C>> for JsonOrProtobuf<T>
+where
+    C: Codecs
+{
+    type Error = ContentTypeError;
+
+    fn try_from(value: Negotiated<T, C>) -> Result<Self, Self::Error> {
+        let (body, content_type) = value.decompose();
+
+        Self::new(body, &content_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_keeps_byte_strings_and_tags() {
+        use ciborium::{tag::Required, value::Value};
+        use serde::Deserialize;
+
+        use super::*;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Upload {
+            #[serde(with = "serde_bytes")]
+            payload: Vec<u8>,
+            created: Required<u64, 1>,
+        }
+
+        let upload = Upload { payload: vec![0, 1, 255], created: Required(1_700_000_000) };
+        let encoded = CborCodec::encode(&upload).unwrap();
+
+        let value: Value = ciborium::from_reader(encoded.as_ref()).unwrap();
+        let fields = value.into_map().unwrap();
+
+        assert_eq!(fields[0].1, Value::Bytes(vec![0, 1, 255]));
+        assert_eq!(fields[1].1, Value::Tag(1, Box::new(Value::Integer(1_700_000_000.into()))));
+        assert_eq!(<CborCodec as Decode<Upload>>::decode(encoded).unwrap(), upload);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_reads_tagged_values_into_plain_fields() {
+        use ciborium::value::Value;
+        use serde::Deserialize;
+
+        use super::*;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Event {
+            created: u64,
+        }
+
+        let tagged = Value::Map(vec![(Value::Text("created".to_string()), Value::Tag(1, Box::new(Value::Integer(5.into()))))]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&tagged, &mut encoded).unwrap();
+
+        assert_eq!(<CborCodec as Decode<Event>>::decode(encoded.into()).unwrap(), Event { created: 5 });
+    }
+}
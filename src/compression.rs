@@ -0,0 +1,436 @@
+use std::io::{self, Read};
+
+use axum::{body::Bytes, http::{header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY}, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+
+use crate::{accept, codec, EncodeBody, Format, JsonOrProtobufRejection, MediaType};
+
+/// The largest request body accepted once decompressed, matching axum's default body limit.
+pub const DEFAULT_DECOMPRESSED_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Encoded bodies smaller than this are sent uncompressed by [`Compressed`].
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// A content coding from the `Content-Encoding` or `Accept-Encoding` headers. Features add more,
+/// so matches need a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Encoding {
+    Identity,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "br")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    /// Every supported encoding, listed in order of server preference.
+    pub const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd,
+        #[cfg(feature = "br")]
+        Encoding::Brotli,
+        #[cfg(feature = "gzip")]
+        Encoding::Gzip,
+        #[cfg(feature = "deflate")]
+        Encoding::Deflate,
+        Encoding::Identity,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => "deflate",
+            #[cfg(feature = "br")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+
+        if name.eq_ignore_ascii_case("x-gzip") {
+            return Self::from_name("gzip");
+        }
+
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|encoding| encoding.as_str().eq_ignore_ascii_case(name))
+    }
+
+    /// The value to advertise in `Accept-Encoding` when refusing an unsupported encoding.
+    pub(crate) fn accept_encoding() -> String {
+        Self::ALL
+            .iter()
+            .map(|encoding| encoding.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Negotiates a response encoding from the `Accept-Encoding` header.
+    ///
+    /// Each encoding takes the quality of its own entry, or else of `*`, with `identity` acceptable
+    /// unless ruled out. The highest quality wins and ties go to the order of [`Encoding::ALL`]. A
+    /// missing header, or one ruling everything out, leaves the body uncompressed.
+    pub fn from_accept_encoding(headers: &HeaderMap) -> Self {
+        let entries: Vec<(String, u16)> = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|header_value| header_value.to_str().ok())
+            .flat_map(|header_value| header_value.split(','))
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let name = parts.next()?.trim().to_ascii_lowercase();
+
+                if name.is_empty() {
+                    return None;
+                }
+
+                let quality = match parts.find_map(|param| param.trim().strip_prefix("q=")) {
+                    Some(quality) => accept::parse_quality(quality.trim())?,
+                    None => 1000,
+                };
+
+                Some((name, quality))
+            })
+            .collect();
+
+        let quality_of = |name: &str| entries
+            .iter()
+            .find(|(entry, _)| entry == name || (name == "gzip" && entry == "x-gzip"))
+            .map(|(_, quality)| *quality);
+
+        let wildcard = quality_of("*");
+
+        Self::ALL
+            .iter()
+            .copied()
+            .filter_map(|encoding| {
+                let quality = quality_of(encoding.as_str())
+                    .or(wildcard)
+                    .unwrap_or(if encoding == Encoding::Identity { 1 } else { 0 });
+
+                (quality > 0).then_some((encoding, quality))
+            })
+            .fold(None, |best: Option<(Encoding, u16)>, current| match best {
+                Some(best) if best.1 >= current.1 => Some(best),
+                _ => Some(current),
+            })
+            .map_or(Encoding::Identity, |(encoding, _)| encoding)
+    }
+
+    fn decoder<'a>(self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Encoding::Identity => reader,
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(reader)),
+            #[cfg(feature = "br")]
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(reader, 4096)),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        })
+    }
+
+    #[cfg_attr(not(any(feature = "gzip", feature = "deflate", feature = "br", feature = "zstd")), allow(unused_variables))]
+    fn compress(self, bytes: &[u8], level: CompressionLevel) -> io::Result<Vec<u8>> {
+        #[cfg(any(feature = "gzip", feature = "deflate", feature = "br"))]
+        use std::io::Write;
+
+        match self {
+            Encoding::Identity => Ok(bytes.to_vec()),
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level.clamp(1, 6, 9) as u32));
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            },
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(level.clamp(1, 6, 9) as u32));
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            },
+            #[cfg(feature = "br")]
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level.clamp(0, 4, 11) as u32, 22);
+                encoder.write_all(bytes)?;
+                Ok(encoder.into_inner())
+            },
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::bulk::compress(bytes, level.clamp(1, 3, 19)),
+        }
+    }
+}
+
+/// How hard [`Compressed`] works at compressing a response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    Fastest,
+    #[default]
+    Default,
+    Best,
+    /// An encoder specific level, clamped to the range the encoding supports.
+    Precise(i32),
+}
+
+impl CompressionLevel {
+    #[cfg_attr(not(any(feature = "gzip", feature = "deflate", feature = "br", feature = "zstd")), allow(dead_code))]
+    fn clamp(self, fastest: i32, default: i32, best: i32) -> i32 {
+        match self {
+            CompressionLevel::Fastest => fastest,
+            CompressionLevel::Default => default,
+            CompressionLevel::Best => best,
+            CompressionLevel::Precise(level) => level.clamp(fastest, best),
+        }
+    }
+}
+
+/// Compresses an encoded response body with the encoding negotiated from `Accept-Encoding`.
+///
+/// Bodies below the threshold are sent as is, and `Vary: Accept-Encoding` is always added since the
+/// response depends on the header either way.
+pub struct Compressed<B> {
+    body: B,
+    encoding: Encoding,
+    threshold: usize,
+    levels: Vec<(Format, CompressionLevel)>,
+}
+
+impl<B> Compressed<B> {
+    pub fn new(body: B, headers: &HeaderMap) -> Self {
+        Self::with_encoding(body, Encoding::from_accept_encoding(headers))
+    }
+
+    pub fn with_encoding(body: B, encoding: Encoding) -> Self {
+        Self {
+            body,
+            encoding,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            levels: Vec::new(),
+        }
+    }
+
+    /// Sets the smallest encoded body, in bytes, worth compressing.
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the compression level for bodies in `format`, which otherwise use
+    /// [`CompressionLevel::Default`].
+    pub fn level(mut self, format: Format, level: CompressionLevel) -> Self {
+        self.levels.retain(|(existing, _)| *existing != format);
+        self.levels.push((format, level));
+        self
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn into_inner(self) -> B {
+        self.body
+    }
+
+    fn level_for(&self, content_type: &str) -> CompressionLevel {
+        let format = content_type
+            .parse::<MediaType>()
+            .ok()
+            .and_then(|media_type| Format::from_media_type(&media_type));
+
+        self.levels
+            .iter()
+            .find(|(existing, _)| Some(*existing) == format)
+            .map_or(CompressionLevel::Default, |(_, level)| *level)
+    }
+}
+
+impl<B> IntoResponse for Compressed<B>
+where
+    B: EncodeBody
+{
+    fn into_response(self) -> Response {
+        let (content_type, encoded) = self.body.encode_body();
+
+        let bytes = match encoded {
+            Ok(bytes) => bytes,
+            Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        };
+
+        let compressed = (self.encoding != Encoding::Identity && bytes.len() >= self.threshold)
+            .then(|| self.encoding.compress(&bytes, self.level_for(&content_type)).ok())
+            .flatten();
+
+        let mut response = match compressed {
+            Some(compressed) => {
+                let mut response = codec::encoded_response(&content_type, Ok(compressed.into()));
+                response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static(self.encoding.as_str()));
+                response
+            },
+            None => codec::encoded_response(&content_type, Ok(bytes)),
+        };
+
+        if self.body.negotiated() {
+            codec::vary_accept(&mut response);
+        }
+
+        response.headers_mut().append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        response
+    }
+}
+
+/// The encodings listed in `Content-Encoding`, in the order they were applied.
+pub(crate) fn content_encodings(headers: &HeaderMap) -> Result<Vec<Encoding>, JsonOrProtobufRejection> {
+    let mut encodings = Vec::new();
+
+    for header_value in headers.get_all(CONTENT_ENCODING) {
+        let header_value = header_value
+            .to_str()
+            .map_err(|_| JsonOrProtobufRejection::UnsupportedContentEncoding(String::from_utf8_lossy(header_value.as_bytes()).into_owned()))?;
+
+        for name in header_value.split(',').filter(|name| !name.trim().is_empty()) {
+            let Some(encoding) = Encoding::from_name(name) else {
+                return Err(JsonOrProtobufRejection::UnsupportedContentEncoding(name.trim().to_string()));
+            };
+
+            if encoding != Encoding::Identity {
+                encodings.push(encoding);
+            }
+        }
+    }
+
+    Ok(encodings)
+}
+
+/// Undoes `encodings`, refusing output larger than `limit` bytes so a small compressed body cannot
+/// expand without bound.
+pub(crate) fn decompress(bytes: Bytes, encodings: &[Encoding], limit: usize) -> Result<Bytes, JsonOrProtobufRejection> {
+    if encodings.is_empty() {
+        return Ok(bytes);
+    }
+
+    let mut reader: Box<dyn Read> = Box::new(bytes.as_ref());
+
+    for encoding in encodings.iter().rev() {
+        reader = encoding
+            .decoder(reader)
+            .map_err(JsonOrProtobufRejection::DecompressionError)?;
+    }
+
+    let mut decompressed = Vec::new();
+
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(JsonOrProtobufRejection::DecompressionError)?;
+
+    if decompressed.len() > limit {
+        return Err(JsonOrProtobufRejection::DecompressedTooLarge { limit });
+    }
+
+    Ok(decompressed.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::CONTENT_TYPE;
+
+    use super::*;
+
+    fn content_encoding(value: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(CONTENT_ENCODING, HeaderValue::from_static(value))])
+    }
+
+    #[test]
+    fn refuses_unknown_encodings_with_an_accept_encoding_hint() {
+        let Err(rejection) = content_encodings(&content_encoding("identity, compress")) else {
+            panic!("`compress` is not supported");
+        };
+
+        assert!(matches!(&rejection, JsonOrProtobufRejection::UnsupportedContentEncoding(name) if name == "compress"));
+
+        let response = rejection.into_response();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(response.headers()[ACCEPT_ENCODING], Encoding::accept_encoding().as_str());
+    }
+
+    #[test]
+    fn skips_identity() {
+        assert_eq!(content_encodings(&content_encoding("identity")).unwrap(), []);
+        assert_eq!(decompress(Bytes::from_static(b"{}"), &[], 1).unwrap(), "{}");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn reads_x_gzip_as_gzip() {
+        assert_eq!(content_encodings(&content_encoding("x-gzip")).unwrap(), [Encoding::Gzip]);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn caps_the_decompressed_size() {
+        let bomb: Bytes = Encoding::Gzip.compress(&[0; 64 * 1024], CompressionLevel::Best).unwrap().into();
+
+        assert!(bomb.len() < 1024);
+        assert!(matches!(decompress(bomb.clone(), &[Encoding::Gzip], 1024), Err(JsonOrProtobufRejection::DecompressedTooLarge { limit: 1024 })));
+        assert_eq!(decompress(bomb, &[Encoding::Gzip], 64 * 1024).unwrap().len(), 64 * 1024);
+    }
+
+    #[cfg(all(feature = "gzip", feature = "deflate"))]
+    #[test]
+    fn undoes_encodings_in_reverse_order() {
+        let deflated = Encoding::Deflate.compress(b"{\"a\":1}", CompressionLevel::Default).unwrap();
+        let gzipped: Bytes = Encoding::Gzip.compress(&deflated, CompressionLevel::Default).unwrap().into();
+        let encodings = content_encodings(&content_encoding("deflate, gzip")).unwrap();
+
+        assert_eq!(encodings, [Encoding::Deflate, Encoding::Gzip]);
+        assert_eq!(decompress(gzipped.clone(), &encodings, 1024).unwrap(), "{\"a\":1}");
+        assert!(matches!(decompress(gzipped, &[Encoding::Gzip, Encoding::Deflate], 1024), Err(JsonOrProtobufRejection::DecompressionError(_))));
+    }
+
+    fn accept_encoding(value: &'static str) -> Encoding {
+        Encoding::from_accept_encoding(&HeaderMap::from_iter([(ACCEPT_ENCODING, HeaderValue::from_static(value))]))
+    }
+
+    #[test]
+    fn leaves_bodies_uncompressed_unless_asked() {
+        assert_eq!(Encoding::from_accept_encoding(&HeaderMap::new()), Encoding::Identity);
+        assert_eq!(accept_encoding("identity;q=0"), Encoding::Identity);
+        assert_eq!(accept_encoding("compress, identity;q=0.5"), Encoding::Identity);
+        assert_eq!(accept_encoding("*"), Encoding::ALL[0]);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn weighs_accept_encoding_entries() {
+        assert_eq!(accept_encoding("gzip"), Encoding::Gzip);
+        assert_eq!(accept_encoding("x-gzip"), Encoding::Gzip);
+        assert_eq!(accept_encoding("gzip;q=0"), Encoding::Identity);
+        assert_eq!(accept_encoding("gzip;q=0.5, identity;q=0"), Encoding::Gzip);
+        assert_eq!(accept_encoding("*;q=0.8, identity;q=0.5"), Encoding::ALL[0]);
+        assert_eq!(accept_encoding("*;q=0.5, identity"), Encoding::Identity);
+        assert_eq!(accept_encoding("gzip;q=0.5, identity;q=0.8"), Encoding::Identity);
+        assert_eq!(accept_encoding("gzip;q=2"), Encoding::Identity);
+    }
+
+    #[cfg(all(feature = "gzip", feature = "br"))]
+    #[test]
+    fn breaks_ties_by_server_preference() {
+        assert_eq!(accept_encoding("gzip, br"), Encoding::Brotli);
+        assert_eq!(accept_encoding("gzip;q=0.9, br;q=0.8"), Encoding::Gzip);
+    }
+}
@@ -0,0 +1,234 @@
+use std::iter;
+
+use axum::{extract::{Request, State}, http::{Extensions, HeaderMap}, middleware::Next, response::Response};
+
+use crate::{accept, codec, AcceptPolicy, BodyLimits, Format, MediaType, CONTENT_TYPE_PROTOBUF, PROTOBUF_CONTENT_TYPES};
+
+/// Configures the [`JsonOrProtobuf`](crate::JsonOrProtobuf) and [`Negotiated`](crate::Negotiated)
+/// extractors.
+///
+/// Supply it as a request extension, either with an [`Extension`](axum::Extension) layer or from
+/// router state through [`with_config`]. The extractors only read the extension, so a configuration
+/// in router state has no effect without the [`with_config`] middleware. Requests without one use
+/// [`JsonOrProtobufConfig::default`].
+#[derive(Clone, Debug)]
+pub struct JsonOrProtobufConfig {
+    formats: Vec<Format>,
+    pub(crate) default_format: Format,
+    pub(crate) strict: bool,
+    strict_accept: bool,
+    pub(crate) limits: Option<BodyLimits>,
+    protobuf_content_type: &'static str,
+}
+
+impl JsonOrProtobufConfig {
+    pub fn new() -> Self {
+        Self {
+            formats: Format::ALL.to_vec(),
+            default_format: Format::Json,
+            strict: true,
+            strict_accept: false,
+            limits: None,
+            protobuf_content_type: CONTENT_TYPE_PROTOBUF,
+        }
+    }
+
+    /// Sets the formats accepted as request bodies, refusing any other with `415`. When not strict
+    /// and `formats` leaves out the default format, the first of them becomes the default.
+    ///
+    /// # Panics
+    ///
+    /// Panics when not strict and `formats` is empty.
+    pub fn formats(mut self, formats: &[Format]) -> Self {
+        self.formats = formats.to_vec();
+        self.fall_back_to_an_accepted_format()
+    }
+
+    /// Sets the format used when the `Accept` header rules out every format, and for bodies without
+    /// a `Content-Type` when not strict.
+    ///
+    /// # Panics
+    ///
+    /// Panics when not strict and `default_format` isn't one of the accepted formats.
+    pub fn default_format(mut self, default_format: Format) -> Self {
+        self.default_format = default_format;
+        self.check_default_format()
+    }
+
+    /// Whether a `Content-Type` is required, rather than reading the body as the default format.
+    /// When not strict and the default format isn't accepted, the first accepted format becomes the
+    /// default.
+    ///
+    /// # Panics
+    ///
+    /// Panics when `strict` is false and no format is accepted.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self.fall_back_to_an_accepted_format()
+    }
+
+    /// Whether to refuse with `406` when the `Accept` header rules out every format, rather than
+    /// responding in the default format.
+    pub fn strict_accept(mut self, strict_accept: bool) -> Self {
+        self.strict_accept = strict_accept;
+        self
+    }
+
+    /// Sets the body limits, which a [`BodyLimits`] extension on a route still overrides.
+    pub fn limits(mut self, limits: BodyLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Sets the `Content-Type` of protobuf responses, e.g. `application/x-protobuf`, in place of
+    /// [`CONTENT_TYPE_PROTOBUF`]. Clients that only accept other aliases get the one they prefer.
+    ///
+    /// Only [`with_config`] can apply this, as an [`Extension`](axum::Extension) never sees the
+    /// response.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `content_type` is one of [`PROTOBUF_CONTENT_TYPES`].
+    pub fn protobuf_content_type(mut self, content_type: &str) -> Self {
+        self.protobuf_content_type = content_type
+            .parse::<MediaType>()
+            .ok()
+            .and_then(|media_type| PROTOBUF_CONTENT_TYPES.into_iter().find(|protobuf| *protobuf == media_type.essence()))
+            .unwrap_or_else(|| panic!("`{content_type}` is not a protobuf content type"));
+        self
+    }
+
+    // Bodies without a `Content-Type` are read as the default format when not strict, which would
+    // otherwise let through a format the configuration refuses.
+    fn check_default_format(self) -> Self {
+        if !self.strict && !self.accepts(self.default_format) {
+            panic!("the default format {:?} is not one of the accepted formats {:?}", self.default_format, self.formats);
+        }
+
+        self
+    }
+
+    fn fall_back_to_an_accepted_format(mut self) -> Self {
+        if !self.strict && !self.accepts(self.default_format) {
+            self.default_format = *self.formats.first().expect("a configuration that isn't strict must accept a format");
+        }
+
+        self
+    }
+
+    // The configuration supplied for a request, or the default.
+    pub(crate) fn of(extensions: &Extensions) -> Self {
+        extensions.get::<Self>().cloned().unwrap_or_default()
+    }
+
+    // The configured protobuf type, or the alias the client prefers when it accepts another.
+    fn negotiate_protobuf_content_type(&self, headers: &HeaderMap) -> &'static str {
+        let aliases: Vec<&str> = iter::once(self.protobuf_content_type)
+            .chain(PROTOBUF_CONTENT_TYPES.into_iter().filter(|alias| *alias != self.protobuf_content_type))
+            .collect();
+
+        accept::accept_header(headers)
+            .and_then(|accept| accept::negotiate(&accept, &aliases))
+            .map_or(self.protobuf_content_type, |index| aliases[index])
+    }
+
+    pub fn accepts(&self, format: Format) -> bool {
+        self.formats.contains(&format)
+    }
+
+    // Whether bodies of this media type are accepted. Codecs outside the built-in formats can't be
+    // configured away.
+    pub(crate) fn accepts_media_type(&self, essence: &str) -> bool {
+        Format::ALL
+            .iter()
+            .filter(|format| format.media_types().contains(&essence))
+            .all(|format| self.accepts(*format))
+    }
+
+    pub fn accept_policy(&self) -> AcceptPolicy {
+        if self.strict_accept {
+            AcceptPolicy::Strict
+        } else {
+            AcceptPolicy::Fallback(self.default_format)
+        }
+    }
+}
+
+impl Default for JsonOrProtobufConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+tokio::task_local! {
+    // The protobuf `Content-Type` picked by `with_config` for the request being handled.
+    static PROTOBUF_CONTENT_TYPE: &'static str;
+}
+
+/// Middleware inserting the [`JsonOrProtobufConfig`] from router state into each request, for use
+/// with [`axum::middleware::from_fn_with_state`] and any state implementing `FromRef`, including the
+/// configuration itself.
+///
+/// Also gives protobuf responses built while handling the request the configured `Content-Type`,
+/// or the alias preferred by the `Accept` header.
+pub async fn with_config(State(config): State<JsonOrProtobufConfig>, mut request: Request, next: Next) -> Response {
+    let protobuf_content_type = config.negotiate_protobuf_content_type(request.headers());
+
+    request.extensions_mut().insert(config);
+
+    let mut response = PROTOBUF_CONTENT_TYPE.scope(protobuf_content_type, next.run(request)).await;
+
+    // The alias depends on `Accept` even when the header is missing.
+    if response.extensions().get::<Format>() == Some(&Format::Protobuf) {
+        codec::vary_accept(&mut response);
+    }
+
+    response
+}
+
+// The `Content-Type` of protobuf responses, as picked by `with_config` when running behind it.
+pub(crate) fn protobuf_content_type() -> &'static str {
+    PROTOBUF_CONTENT_TYPE
+        .try_with(|content_type| *content_type)
+        .unwrap_or(CONTENT_TYPE_PROTOBUF)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::ACCEPT, HeaderValue};
+
+    use super::*;
+
+    fn accepting(accept: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(ACCEPT, HeaderValue::from_static(accept))])
+    }
+
+    #[test]
+    fn protobuf_responses_use_the_alias_the_client_prefers() {
+        let config = JsonOrProtobufConfig::new().protobuf_content_type("application/x-protobuf");
+
+        assert_eq!(config.negotiate_protobuf_content_type(&HeaderMap::new()), "application/x-protobuf");
+        assert_eq!(config.negotiate_protobuf_content_type(&accepting("*/*")), "application/x-protobuf");
+        assert_eq!(config.negotiate_protobuf_content_type(&accepting("application/protobuf")), "application/protobuf");
+        assert_eq!(config.negotiate_protobuf_content_type(&accepting("application/json")), "application/x-protobuf");
+    }
+
+    #[test]
+    fn non_strict_configurations_accept_their_default_format() {
+        let narrowed = JsonOrProtobufConfig::new().strict(false).formats(&[Format::Protobuf]);
+        let relaxed = JsonOrProtobufConfig::new().formats(&[Format::Protobuf]).strict(false);
+        let strict = JsonOrProtobufConfig::new().formats(&[Format::Protobuf]);
+
+        assert_eq!(narrowed.default_format, Format::Protobuf);
+        assert_eq!(relaxed.default_format, Format::Protobuf);
+        assert_eq!(strict.default_format, Format::Json);
+        assert!(std::panic::catch_unwind(|| relaxed.default_format(Format::Json)).is_err());
+        assert!(std::panic::catch_unwind(|| JsonOrProtobufConfig::new().strict(false).formats(&[])).is_err());
+    }
+
+    #[test]
+    fn content_type_follows_with_config_only_while_handling_a_request() {
+        assert_eq!(protobuf_content_type(), CONTENT_TYPE_PROTOBUF);
+        assert_eq!(PROTOBUF_CONTENT_TYPE.sync_scope("application/x-protobuf", protobuf_content_type), "application/x-protobuf");
+    }
+}
@@ -0,0 +1,213 @@
+use std::iter;
+
+use axum::http::HeaderMap;
+
+#[cfg(feature = "cbor")]
+use crate::CONTENT_TYPE_CBOR;
+#[cfg(feature = "grpc-web")]
+use crate::{CONTENT_TYPE_GRPC_WEB, CONTENT_TYPE_GRPC_WEB_PROTO};
+#[cfg(feature = "msgpack")]
+use crate::{CONTENT_TYPE_MSGPACK, CONTENT_TYPE_X_MSGPACK};
+use crate::{accept, config, rejection::NotAcceptable, MediaType, CONTENT_TYPE_JSON, CONTENT_TYPE_PROBLEM_JSON, PROTOBUF_CONTENT_TYPES};
+
+/// What to do when the `Accept` header rules out every supported format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcceptPolicy {
+    /// Refuse with `406 Not Acceptable`.
+    Strict,
+    /// Respond in the given format regardless.
+    Fallback(Format),
+}
+
+impl Default for AcceptPolicy {
+    fn default() -> Self {
+        AcceptPolicy::Fallback(Format::Json)
+    }
+}
+
+/// A supported wire format. Features add more, so matches need a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Format {
+    Protobuf,
+    Json,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// Unary gRPC-Web calls, protobuf messages in gRPC frames.
+    #[cfg(feature = "grpc-web")]
+    GrpcWeb,
+}
+
+impl Format {
+    // Listed in order of server preference, which breaks ties between equally acceptable formats.
+    pub const ALL: &'static [Format] = &[
+        Format::Json,
+        Format::Protobuf,
+        #[cfg(feature = "msgpack")]
+        Format::MsgPack,
+        #[cfg(feature = "cbor")]
+        Format::Cbor,
+        #[cfg(feature = "grpc-web")]
+        Format::GrpcWeb,
+    ];
+
+    /// The `Content-Type` of responses in this format.
+    ///
+    /// For protobuf this is [`CONTENT_TYPE_PROTOBUF`](crate::CONTENT_TYPE_PROTOBUF), unless called
+    /// while handling a request behind [`with_config`](crate::with_config), which picks the type
+    /// from the configuration and the `Accept` header.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Protobuf => config::protobuf_content_type(),
+            Format::Json => CONTENT_TYPE_JSON,
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => CONTENT_TYPE_MSGPACK,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CONTENT_TYPE_CBOR,
+            #[cfg(feature = "grpc-web")]
+            Format::GrpcWeb => CONTENT_TYPE_GRPC_WEB_PROTO,
+        }
+    }
+
+    /// Every media type recognised as this format.
+    pub fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Protobuf => &PROTOBUF_CONTENT_TYPES,
+            Format::Json => &[CONTENT_TYPE_JSON],
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => &[CONTENT_TYPE_MSGPACK, CONTENT_TYPE_X_MSGPACK],
+            #[cfg(feature = "cbor")]
+            Format::Cbor => &[CONTENT_TYPE_CBOR],
+            #[cfg(feature = "grpc-web")]
+            Format::GrpcWeb => &[CONTENT_TYPE_GRPC_WEB_PROTO, CONTENT_TYPE_GRPC_WEB],
+        }
+    }
+
+    pub(crate) fn all_media_types() -> Vec<&'static str> {
+        Self::ALL
+            .iter()
+            .flat_map(|format| format.media_types().iter().copied())
+            .collect()
+    }
+
+    pub fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|format| format.media_types().contains(&media_type.essence()))
+    }
+
+    /// Whether a body in this format can be read with the given `charset` parameter.
+    pub fn supports_charset(self, charset: &str) -> bool {
+        match self {
+            Format::Json => charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("utf8"),
+            _ => true,
+        }
+    }
+
+    /// Negotiates a format from the `Accept` header.
+    ///
+    /// A missing header accepts any format, so the most preferred one is chosen. Returns `None` when
+    /// the header is present but none of the supported formats are acceptable. Protobuf is ruled
+    /// out when the [`Format::content_type`] it would be sent as is refused, and gRPC-Web requests
+    /// always get gRPC-Web, whatever they accept.
+    pub fn from_accept_header(headers: &HeaderMap) -> Option<Self> {
+        if let Some(format) = Self::required_by(headers) {
+            return Some(format);
+        }
+
+        accept::negotiate_groups(headers, &Self::acceptable_media_types(Self::ALL, headers)).map(|index| Self::ALL[index])
+    }
+
+    /// Like [`Format::from_accept_header`], but picks `preferred` whenever it is as acceptable as
+    /// any other format, including when the header is missing.
+    pub fn from_accept_header_preferring(headers: &HeaderMap, preferred: Format) -> Option<Self> {
+        if let Some(format) = Self::required_by(headers) {
+            return Some(format);
+        }
+
+        let formats: Vec<_> = iter::once(preferred)
+            .chain(Self::ALL.iter().copied().filter(|format| *format != preferred))
+            .collect();
+
+        accept::negotiate_groups(headers, &Self::acceptable_media_types(&formats, headers)).map(|index| formats[index])
+    }
+
+    // Like `from_accept_header`, for problem documents, which clients may accept as
+    // `application/problem+json` rather than as JSON.
+    pub(crate) fn from_accept_header_for_problems(headers: &HeaderMap) -> Option<Self> {
+        if let Some(format) = Self::required_by(headers) {
+            return Some(format);
+        }
+
+        let media_types: Vec<Vec<&str>> = Self::ALL
+            .iter()
+            .zip(Self::acceptable_media_types(Self::ALL, headers))
+            .map(|(format, media_types)| match format {
+                Format::Json => iter::once(CONTENT_TYPE_PROBLEM_JSON).chain(media_types.iter().copied()).collect(),
+                _ => media_types.to_vec(),
+            })
+            .collect();
+
+        let groups: Vec<&[&str]> = media_types.iter().map(Vec::as_slice).collect();
+
+        accept::negotiate_groups(headers, &groups).map(|index| Self::ALL[index])
+    }
+
+    // Protobuf is left out when the client refuses the type it would be sent as, even if it accepts
+    // another alias.
+    fn acceptable_media_types(formats: &[Format], headers: &HeaderMap) -> Vec<&'static [&'static str]> {
+        let protobuf_refused = accept::refuses(headers, Format::Protobuf.content_type());
+
+        formats
+            .iter()
+            .map(|format| match format {
+                Format::Protobuf if protobuf_refused => &[],
+                _ => format.media_types(),
+            })
+            .collect()
+    }
+
+    // gRPC-Web clients read the response as frames and errors from the trailers, so they can't be
+    // answered in anything else.
+    #[cfg_attr(not(feature = "grpc-web"), allow(unused_variables))]
+    fn required_by(headers: &HeaderMap) -> Option<Self> {
+        #[cfg(feature = "grpc-web")]
+        if crate::grpc_web::is_grpc_web(headers) {
+            return Some(Format::GrpcWeb);
+        }
+
+        None
+    }
+
+    pub fn negotiate(headers: &HeaderMap, policy: AcceptPolicy) -> Result<Self, NotAcceptable> {
+        match (Self::from_accept_header(headers), policy) {
+            (Some(format), _) => Ok(format),
+            (None, AcceptPolicy::Fallback(format)) => Ok(format),
+            (None, AcceptPolicy::Strict) => Err(NotAcceptable::new(Self::all_media_types())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::ACCEPT, HeaderValue};
+
+    use super::*;
+
+    fn accepting(accept: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(ACCEPT, HeaderValue::from_static(accept))])
+    }
+
+    #[test]
+    fn problems_treat_problem_json_as_json() {
+        let headers = accepting("application/problem+json, application/x-protobuf;q=0.1");
+
+        assert_eq!(Format::from_accept_header(&headers), Some(Format::Protobuf));
+        assert_eq!(Format::from_accept_header_for_problems(&headers), Some(Format::Json));
+        assert_eq!(Format::from_accept_header_for_problems(&accepting("application/x-protobuf")), Some(Format::Protobuf));
+        assert_eq!(Format::from_accept_header_for_problems(&accepting("text/html")), None);
+    }
+}
@@ -0,0 +1,157 @@
+use axum::{body::Bytes, http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+
+use crate::{media_type::MediaType, Format, JsonOrProtobufRejection, CONTENT_TYPE_GRPC_WEB_PROTO};
+
+const COMPRESSED_FLAG: u8 = 0x01;
+const TRAILER_FLAG: u8 = 0x80;
+
+const GRPC_STATUS_OK: u32 = 0;
+
+/// Strips the frame from a unary request, which carries exactly one uncompressed message.
+pub(crate) fn unframe(bytes: Bytes) -> Result<Bytes, JsonOrProtobufRejection> {
+    if bytes.len() < 5 {
+        return Err(JsonOrProtobufRejection::GrpcWebFrameError("missing frame header"));
+    }
+
+    if bytes[0] & TRAILER_FLAG != 0 {
+        return Err(JsonOrProtobufRejection::GrpcWebFrameError("expected a message frame"));
+    }
+
+    if bytes[0] & COMPRESSED_FLAG != 0 {
+        return Err(JsonOrProtobufRejection::GrpcWebFrameError("compressed messages are not supported"));
+    }
+
+    let length = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+
+    if bytes.len() - 5 != length {
+        return Err(JsonOrProtobufRejection::GrpcWebFrameError("frame length does not match the body"));
+    }
+
+    Ok(bytes.slice(5..))
+}
+
+/// Frames a unary response: the message followed by trailers reporting success.
+pub(crate) fn frame(message: &[u8]) -> Bytes {
+    let mut body = Vec::with_capacity(message.len() + 32);
+
+    push_frame(&mut body, 0, message);
+    push_frame(&mut body, TRAILER_FLAG, trailers(GRPC_STATUS_OK, "").as_bytes());
+
+    body.into()
+}
+
+/// A trailers-only response for a failed call. gRPC-Web reports errors through `grpc-status`
+/// rather than the HTTP status, so the response itself is `200 OK`.
+pub(crate) fn error_response(status: StatusCode, message: &str) -> Response {
+    let grpc_status = grpc_status(status);
+    let message = percent_encode(message);
+
+    let mut body = Vec::new();
+    push_frame(&mut body, TRAILER_FLAG, trailers(grpc_status, &message).as_bytes());
+
+    let mut response = ([(CONTENT_TYPE, CONTENT_TYPE_GRPC_WEB_PROTO)], body).into_response();
+    response.headers_mut().insert("grpc-status", HeaderValue::from(grpc_status));
+
+    if let Ok(message) = HeaderValue::from_str(&message) {
+        response.headers_mut().insert("grpc-message", message);
+    }
+
+    response
+}
+
+pub(crate) fn is_grpc_web(headers: &HeaderMap) -> bool {
+    matches!(
+        MediaType::from_content_type(headers),
+        Some(Ok(media_type)) if Format::from_media_type(&media_type) == Some(Format::GrpcWeb)
+    )
+}
+
+fn push_frame(body: &mut Vec<u8>, flags: u8, payload: &[u8]) {
+    body.push(flags);
+    body.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    body.extend_from_slice(payload);
+}
+
+fn trailers(grpc_status: u32, message: &str) -> String {
+    let mut trailers = format!("grpc-status:{}\r\n", grpc_status);
+
+    if !message.is_empty() {
+        trailers.push_str(&format!("grpc-message:{}\r\n", message));
+    }
+
+    trailers
+}
+
+// Follows the HTTP to gRPC status mapping for the statuses rejections produce.
+fn grpc_status(status: StatusCode) -> u32 {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY | StatusCode::UNSUPPORTED_MEDIA_TYPE => 3,
+        StatusCode::PAYLOAD_TOO_LARGE => 8,
+        StatusCode::UNAUTHORIZED => 16,
+        StatusCode::FORBIDDEN => 7,
+        StatusCode::NOT_FOUND | StatusCode::NOT_ACCEPTABLE => 12,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => 14,
+        status if status.is_server_error() => 13,
+        _ => 2,
+    }
+}
+
+// `grpc-message` is percent-encoded, leaving printable ASCII other than `%` as is.
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_error(bytes: &'static [u8]) -> Option<&'static str> {
+        match unframe(Bytes::from_static(bytes)) {
+            Err(JsonOrProtobufRejection::GrpcWebFrameError(reason)) => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn unframes_a_message() {
+        assert_eq!(unframe(Bytes::from_static(b"\x00\x00\x00\x00\x02hi")).unwrap(), "hi");
+        assert_eq!(unframe(Bytes::from_static(b"\x00\x00\x00\x00\x00")).unwrap(), "");
+    }
+
+    #[test]
+    fn refuses_short_frames() {
+        assert_eq!(frame_error(b""), Some("missing frame header"));
+        assert_eq!(frame_error(b"\x00\x00\x00\x00"), Some("missing frame header"));
+    }
+
+    #[test]
+    fn refuses_trailer_frames() {
+        assert_eq!(frame_error(b"\x80\x00\x00\x00\x00"), Some("expected a message frame"));
+    }
+
+    #[test]
+    fn refuses_compressed_frames() {
+        assert_eq!(frame_error(b"\x01\x00\x00\x00\x02hi"), Some("compressed messages are not supported"));
+    }
+
+    #[test]
+    fn refuses_mismatched_lengths() {
+        assert_eq!(frame_error(b"\x00\x00\x00\x00\x03hi"), Some("frame length does not match the body"));
+        assert_eq!(frame_error(b"\x00\x00\x00\x00\x01hi"), Some("frame length does not match the body"));
+        assert_eq!(frame_error(b"\x00\xff\xff\xff\xffhi"), Some("frame length does not match the body"));
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let framed = frame(b"hello");
+
+        assert_eq!(unframe(framed.slice(..10)).unwrap(), "hello");
+        assert_eq!(&framed[10..], b"\x80\x00\x00\x00\x0fgrpc-status:0\r\n");
+    }
+}
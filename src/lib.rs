@@ -1,20 +1,104 @@
-use std::{error::Error, fmt::{self, Display}};
+mod accept;
+mod body;
+mod codec;
+mod compression;
+mod config;
+mod format;
+#[cfg(feature = "grpc-web")]
+mod grpc_web;
+mod media_type;
+mod problem;
+#[cfg(feature = "proto3-json")]
+mod proto3_json;
+mod rejection;
+mod response;
+mod response_format;
+mod sse;
+mod stream;
+#[cfg(feature = "ws")]
+mod ws;
 
-use axum::{async_trait, extract::{FromRequest, Request}, http::{header::{ACCEPT, CONTENT_TYPE}, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json, RequestExt};
-use axum_extra::protobuf::Protobuf;
-use prost::Message;
-use serde::Serialize;
+use std::{borrow::Cow, error::Error, fmt::{self, Display}};
 
-pub const CONTENT_TYPE_PROTOBUF: &'static str = "application/octet-stream";
-pub const CONTENT_TYPE_JSON: &'static str = "application/json";
+use axum::{async_trait, body::Bytes, extract::{FromRequest, Request}, http::HeaderMap, response::{IntoResponse, Response}};
+use prost::{Message, Name};
+use serde::{de::DeserializeOwned, Serialize};
 
-pub enum JsonOrProtobuf<T> {
-    Protobuf(T),
-    Json(T),
+pub use body::BodyLimits;
+pub use codec::{Codec, Codecs, Decode, DecodeWith, DefaultCodecs, Encode, EncodeBody, EncodeWith, JsonCodec, JsonOnly, NamedProtobufCodec, Negotiated, ProtobufCodec, ProtobufOnly};
+#[cfg(feature = "cbor")]
+pub use codec::CborCodec;
+#[cfg(feature = "grpc-web")]
+pub use codec::GrpcWebCodec;
+#[cfg(feature = "msgpack")]
+pub use codec::MsgPackCodec;
+pub use compression::{Compressed, CompressionLevel, Encoding, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_DECOMPRESSED_LIMIT};
+pub use config::{with_config, JsonOrProtobufConfig};
+pub use format::{AcceptPolicy, Format};
+pub use media_type::MediaType;
+pub use problem::{Problem, ProblemRejection, CONTENT_TYPE_PROBLEM_JSON};
+#[cfg(feature = "proto3-json")]
+pub use proto3_json::Proto3Json;
+pub use rejection::{JsonOrProtobufRejection, NotAcceptable};
+pub use response::{FromAccept, NegotiatedResponse};
+pub use response_format::ResponseFormat;
+pub use sse::{SseEvent, SseResponse};
+pub use stream::{JsonOrProtobufStream, StreamError, StreamFormat, StreamResponse};
+#[cfg(feature = "ws")]
+pub use ws::{JsonOrProtobufSocket, WS_PROTOCOL_JSON, WS_PROTOCOL_PROTOBUF};
+
+/// The default `Content-Type` of protobuf responses, see [`JsonOrProtobufConfig::protobuf_content_type`].
+pub const CONTENT_TYPE_PROTOBUF: &str = "application/octet-stream";
+pub const CONTENT_TYPE_X_PROTOBUF: &str = "application/x-protobuf";
+pub const CONTENT_TYPE_APPLICATION_PROTOBUF: &str = "application/protobuf";
+pub const CONTENT_TYPE_VND_GOOGLE_PROTOBUF: &str = "application/vnd.google.protobuf";
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_NDJSON: &str = "application/x-ndjson";
+pub const CONTENT_TYPE_MSGPACK: &str = "application/msgpack";
+pub const CONTENT_TYPE_X_MSGPACK: &str = "application/x-msgpack";
+pub const CONTENT_TYPE_CBOR: &str = "application/cbor";
+pub const CONTENT_TYPE_GRPC_WEB: &str = "application/grpc-web";
+pub const CONTENT_TYPE_GRPC_WEB_PROTO: &str = "application/grpc-web+proto";
+
+/// Every media type accepted as a protobuf request body.
+pub const PROTOBUF_CONTENT_TYPES: [&str; 4] = [
+    CONTENT_TYPE_X_PROTOBUF,
+    CONTENT_TYPE_APPLICATION_PROTOBUF,
+    CONTENT_TYPE_VND_GOOGLE_PROTOBUF,
+    CONTENT_TYPE_PROTOBUF,
+];
+
+/// The protobuf `Content-Type` carrying the `messageType` parameter for `M`, for handlers that
+/// want to advertise the message on the wire. See [`Format::content_type`] for the media type.
+pub fn protobuf_content_type_for<M: Name>() -> String {
+    format!("{}; messageType=\"{}\"", Format::Protobuf.content_type(), M::full_name())
+}
+
+/// A body in any of the supported formats.
+pub type JsonOrProtobuf<T> = SplitJsonOrProtobuf<T, T>;
+
+/// A body in any of the supported formats, with a `J` for the serde formats and a `P` for the
+/// protobuf formats.
+///
+/// [`SplitJsonOrProtobuf::into_domain`] and [`SplitJsonOrProtobuf::from_domain`] convert to and from a
+/// common type. When both are the same type, use the [`JsonOrProtobuf`] alias instead, which lets
+/// the compiler infer both from a single variant.
+///
+/// Features add variants for more formats, so matches need a wildcard arm.
+#[non_exhaustive]
+pub enum SplitJsonOrProtobuf<J, P> {
+    Protobuf(P),
+    Json(J),
+    #[cfg(feature = "msgpack")]
+    MsgPack(J),
+    #[cfg(feature = "cbor")]
+    Cbor(J),
+    #[cfg(feature = "grpc-web")]
+    GrpcWeb(P),
 }
 
 #[derive(Debug)]
-pub struct ContentTypeError(String);
+pub struct ContentTypeError(pub(crate) String);
 
 impl Error for ContentTypeError {}
 
@@ -24,33 +108,169 @@ impl Display for ContentTypeError {
     }
 }
 
+impl<J, P> SplitJsonOrProtobuf<J, P> {
+    pub fn format(&self) -> Format {
+        match self {
+            SplitJsonOrProtobuf::Protobuf(_) => Format::Protobuf,
+            SplitJsonOrProtobuf::Json(_) => Format::Json,
+            #[cfg(feature = "msgpack")]
+            SplitJsonOrProtobuf::MsgPack(_) => Format::MsgPack,
+            #[cfg(feature = "cbor")]
+            SplitJsonOrProtobuf::Cbor(_) => Format::Cbor,
+            #[cfg(feature = "grpc-web")]
+            SplitJsonOrProtobuf::GrpcWeb(_) => Format::GrpcWeb,
+        }
+    }
+
+    /// Wraps `body` in the same format, e.g. to reply in the format of the request.
+    pub fn with_body<U>(&self, body: U) -> JsonOrProtobuf<U> {
+        JsonOrProtobuf::from_format(body, self.format())
+    }
+
+    /// Wraps `body` in the format preferred by the `Accept` header, falling back to the format of
+    /// this request when the header is missing, indifferent or rules out every format.
+    ///
+    /// Wrap the result in [`FromAccept`] for its response to carry `Vary: Accept`.
+    pub fn reply<U>(&self, body: U, headers: &HeaderMap) -> JsonOrProtobuf<U> {
+        let format = Format::from_accept_header_preferring(headers, self.format()).unwrap_or(self.format());
+
+        JsonOrProtobuf::from_format(body, format)
+    }
+
+    /// Converts a domain type into the body for `format`.
+    pub fn from_domain<T>(value: T, format: Format) -> Self
+    where
+        T: Into<J> + Into<P>
+    {
+        match format {
+            Format::Protobuf => Self::Protobuf(value.into()),
+            Format::Json => Self::Json(value.into()),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => Self::MsgPack(value.into()),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Self::Cbor(value.into()),
+            #[cfg(feature = "grpc-web")]
+            Format::GrpcWeb => Self::GrpcWeb(value.into()),
+        }
+    }
+
+    /// Normalises either body into a domain type.
+    pub fn into_domain<T>(self) -> T
+    where
+        J: Into<T>,
+        P: Into<T>
+    {
+        match self {
+            SplitJsonOrProtobuf::Protobuf(body) => body.into(),
+            SplitJsonOrProtobuf::Json(body) => body.into(),
+            #[cfg(feature = "msgpack")]
+            SplitJsonOrProtobuf::MsgPack(body) => body.into(),
+            #[cfg(feature = "cbor")]
+            SplitJsonOrProtobuf::Cbor(body) => body.into(),
+            #[cfg(feature = "grpc-web")]
+            SplitJsonOrProtobuf::GrpcWeb(body) => body.into(),
+        }
+    }
+
+    /// Like [`SplitJsonOrProtobuf::into_domain`], for conversions that validate.
+    pub fn try_into_domain<T, E>(self) -> Result<T, E>
+    where
+        J: TryInto<T>,
+        P: TryInto<T>,
+        E: From<J::Error> + From<P::Error>
+    {
+        match self {
+            SplitJsonOrProtobuf::Protobuf(body) => Ok(body.try_into()?),
+            SplitJsonOrProtobuf::Json(body) => Ok(body.try_into()?),
+            #[cfg(feature = "msgpack")]
+            SplitJsonOrProtobuf::MsgPack(body) => Ok(body.try_into()?),
+            #[cfg(feature = "cbor")]
+            SplitJsonOrProtobuf::Cbor(body) => Ok(body.try_into()?),
+            #[cfg(feature = "grpc-web")]
+            SplitJsonOrProtobuf::GrpcWeb(body) => Ok(body.try_into()?),
+        }
+    }
+}
+
 impl<T> JsonOrProtobuf<T> {
     pub fn new(body: T, content_type: &str) -> Result<Self, ContentTypeError> {
-        match content_type {
-            CONTENT_TYPE_PROTOBUF => Ok(Self::Protobuf(body)),
-            CONTENT_TYPE_JSON => Ok(Self::Json(body)),
-            _ => Err(ContentTypeError(content_type.to_string()))
+        let media_type: MediaType = content_type.parse()?;
+
+        match Format::from_media_type(&media_type) {
+            Some(format) => Ok(Self::from_format(body, format)),
+            None => Err(ContentTypeError(content_type.to_string()))
         }
     }
 
+    pub fn from_format(body: T, format: Format) -> Self {
+        match format {
+            Format::Protobuf => Self::Protobuf(body),
+            Format::Json => Self::Json(body),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => Self::MsgPack(body),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Self::Cbor(body),
+            #[cfg(feature = "grpc-web")]
+            Format::GrpcWeb => Self::GrpcWeb(body),
+        }
+    }
+
+    /// Picks the response format from the `Accept` header, falling back to JSON when no supported
+    /// format is acceptable.
+    ///
+    /// See [`FromAccept::from_accept_header`] for a body whose response carries `Vary: Accept`.
     pub fn from_accept_header(body: T, headers: &HeaderMap) -> Self {
-        let accept = headers
-            .get(ACCEPT)
-            .and_then(|header_value| header_value.to_str().ok());
-
-        if accept == Some(CONTENT_TYPE_PROTOBUF) {
-            Self::Protobuf(body)
-        } else {
-            Self::Json(body)
+        let format = Format::from_accept_header(headers).unwrap_or(Format::Json);
+
+        Self::from_format(body, format)
+    }
+
+    /// Like [`JsonOrProtobuf::from_accept_header`], but refuses with [`NotAcceptable`] instead of
+    /// falling back to JSON.
+    pub fn try_from_accept_header(body: T, headers: &HeaderMap) -> Result<Self, NotAcceptable> {
+        Self::negotiate(body, headers, AcceptPolicy::Strict)
+    }
+
+    pub fn negotiate(body: T, headers: &HeaderMap, policy: AcceptPolicy) -> Result<Self, NotAcceptable> {
+        Format::negotiate(headers, policy).map(|format| Self::from_format(body, format))
+    }
+
+    pub fn body(&self) -> &T {
+        match self {
+            JsonOrProtobuf::Protobuf(body) | JsonOrProtobuf::Json(body) => body,
+            #[cfg(feature = "msgpack")]
+            JsonOrProtobuf::MsgPack(body) => body,
+            #[cfg(feature = "cbor")]
+            JsonOrProtobuf::Cbor(body) => body,
+            #[cfg(feature = "grpc-web")]
+            JsonOrProtobuf::GrpcWeb(body) => body,
         }
     }
 
-    pub fn decompose(self) -> (T, String) {
+    pub fn into_inner(self) -> T {
         match self {
-            JsonOrProtobuf::Protobuf(body) => (body, CONTENT_TYPE_PROTOBUF.to_string()),
-            JsonOrProtobuf::Json(body) => (body, CONTENT_TYPE_JSON.to_string()),
+            JsonOrProtobuf::Protobuf(body) | JsonOrProtobuf::Json(body) => body,
+            #[cfg(feature = "msgpack")]
+            JsonOrProtobuf::MsgPack(body) => body,
+            #[cfg(feature = "cbor")]
+            JsonOrProtobuf::Cbor(body) => body,
+            #[cfg(feature = "grpc-web")]
+            JsonOrProtobuf::GrpcWeb(body) => body,
         }
     }
+
+    /// Maps the body, keeping the format.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> JsonOrProtobuf<U> {
+        let format = self.format();
+
+        JsonOrProtobuf::from_format(f(self.into_inner()), format)
+    }
+
+    pub fn decompose(self) -> (T, String) {
+        let content_type = self.format().content_type().to_string();
+
+        (self.into_inner(), content_type)
+    }
 }
 
 impl<T> TryFrom<(T, String)> for JsonOrProtobuf<T> {
@@ -61,58 +281,101 @@ impl<T> TryFrom<(T, String)> for JsonOrProtobuf<T> {
     }
 }
 
-impl<T> Into<(T, String)> for JsonOrProtobuf<T> {
-    fn into(self) -> (T, String) {
-        self.decompose()
+impl<T> From<JsonOrProtobuf<T>> for (T, String) {
+    fn from(value: JsonOrProtobuf<T>) -> Self {
+        value.decompose()
     }
 }
 
 #[async_trait]
-impl<'a, T, S> FromRequest<S> for JsonOrProtobuf<T> 
+impl<J, P, S> FromRequest<S> for SplitJsonOrProtobuf<J, P> 
 where
-    T: 'static,
-    Json<T>: FromRequest<()>,
-    Protobuf<T>: FromRequest<()>,
+    J: DeserializeOwned,
+    P: Message + Default,
     S: Send + Sync
 {
-    type Rejection = StatusCode;
-
-    async fn from_request(request: Request, _: &S) -> Result<Self, Self::Rejection> {
-        let content_type = request
-            .headers()
-            .get(CONTENT_TYPE)
-            .and_then(|content_type| content_type.to_str().ok());
-
-        match content_type {
-            Some(CONTENT_TYPE_PROTOBUF) => {
-                let Protobuf(payload) = request
-                    .extract::<Protobuf<T>,_>()
-                    .await
-                    .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-                Ok(Self::Protobuf(payload))
-            },
-            Some(CONTENT_TYPE_JSON) => {
-                let Json(payload) = request
-                    .extract::<Json<T>, _>()
-                    .await
-                    .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-                Ok(Self::Json(payload))
-            },
-            _ => Err(StatusCode::BAD_REQUEST),
-        }
+    type Rejection = ProblemRejection;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Negotiated::<Self, codec::FormatCodecs>::from_request(request, state)
+            .await
+            .map(Negotiated::into_inner)
     }
 }
 
-impl<T> IntoResponse for JsonOrProtobuf<T> 
+impl<J, P> EncodeBody for SplitJsonOrProtobuf<J, P>
 where
-    T: Serialize + Message + Default
+    J: Serialize,
+    P: Message
 {
-    fn into_response(self) -> Response {
+    fn encode_body(&self) -> (Cow<'static, str>, Result<Bytes, axum::Error>) {
         match self {
-            JsonOrProtobuf::Protobuf(p) => Protobuf(p).into_response(),
-            JsonOrProtobuf::Json(j) => Json(j).into_response(),
+            SplitJsonOrProtobuf::Protobuf(p) => (ProtobufCodec::content_type().into(), ProtobufCodec::encode(p)),
+            SplitJsonOrProtobuf::Json(j) => (JsonCodec::content_type().into(), JsonCodec::encode(j)),
+            #[cfg(feature = "msgpack")]
+            SplitJsonOrProtobuf::MsgPack(m) => (MsgPackCodec::content_type().into(), MsgPackCodec::encode(m)),
+            #[cfg(feature = "cbor")]
+            SplitJsonOrProtobuf::Cbor(c) => (CborCodec::content_type().into(), CborCodec::encode(c)),
+            #[cfg(feature = "grpc-web")]
+            SplitJsonOrProtobuf::GrpcWeb(g) => (GrpcWebCodec::content_type().into(), GrpcWebCodec::encode(g)),
         }
     }
-}
\ No newline at end of file
+}
+
+impl<J, P> IntoResponse for SplitJsonOrProtobuf<J, P> 
+where
+    J: Serialize,
+    P: Message
+{
+    fn into_response(self) -> Response {
+        codec::body_response(&self)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use axum::{http::{HeaderMap, StatusCode}, routing::post, Router};
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+    struct Item {
+        #[prost(string, tag = "1")]
+        name: String,
+    }
+
+    async fn json_reply(body: JsonOrProtobuf<Item>) -> impl IntoResponse {
+        JsonOrProtobuf::Json(body.into_inner())
+    }
+
+    async fn protobuf_reply(body: JsonOrProtobuf<Item>) -> impl IntoResponse {
+        let item = match body {
+            JsonOrProtobuf::Protobuf(item) => item,
+            other => other.into_inner(),
+        };
+
+        JsonOrProtobuf::Protobuf(item)
+    }
+
+    async fn decomposed(body: JsonOrProtobuf<Item>) -> impl IntoResponse {
+        let (item, content_type) = body.decompose();
+
+        JsonOrProtobuf::new(item, &content_type).map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    }
+
+    async fn accepted(headers: HeaderMap, body: JsonOrProtobuf<Item>) -> Result<JsonOrProtobuf<Item>, NotAcceptable> {
+        let reply: JsonOrProtobuf<Item> = JsonOrProtobuf::from_accept_header(body.into_inner(), &headers);
+        let (item, _) = reply.decompose();
+
+        JsonOrProtobuf::try_from_accept_header(item, &headers)
+    }
+
+    #[test]
+    fn single_type_handlers_infer_the_body_type() {
+        let _: Router = Router::new()
+            .route("/json", post(json_reply))
+            .route("/protobuf", post(protobuf_reply))
+            .route("/decomposed", post(decomposed))
+            .route("/accepted", post(accepted));
+    }
+}
@@ -0,0 +1,264 @@
+use std::{fmt::{self, Display}, str::FromStr};
+
+use axum::{async_trait, extract::FromRequestParts, http::{header::CONTENT_TYPE, request::Parts, HeaderMap}};
+
+use crate::{ContentTypeError, JsonOrProtobufRejection};
+
+/// A parsed media type such as `application/json; charset=utf-8`.
+///
+/// The type and subtype are compared case-insensitively and parameter names are lowercased. As an
+/// extractor it reads the request's `Content-Type` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaType {
+    essence: String,
+    params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let mut parts = split_unquoted(value, ';').into_iter();
+
+        let essence = parts.next()?.trim();
+        let (type_, subtype) = essence.split_once('/')?;
+
+        if !is_token(type_) || !is_token(subtype) {
+            return None;
+        }
+
+        let mut params = Vec::new();
+
+        for part in parts {
+            let part = part.trim();
+
+            if part.is_empty() {
+                continue;
+            }
+
+            let (name, value) = part.split_once('=')?;
+            let name = name.trim();
+
+            if !is_token(name) {
+                return None;
+            }
+
+            params.push((name.to_ascii_lowercase(), unquote(value.trim())?));
+        }
+
+        Some(Self {
+            essence: essence.to_ascii_lowercase(),
+            params,
+        })
+    }
+
+    pub fn from_content_type(headers: &HeaderMap) -> Option<Result<Self, ContentTypeError>> {
+        let content_type = headers.get(CONTENT_TYPE)?;
+
+        Some(
+            content_type
+                .to_str()
+                .map_err(|_| ContentTypeError(String::from_utf8_lossy(content_type.as_bytes()).into_owned()))
+                .and_then(str::parse),
+        )
+    }
+
+    // The request's media type, or a rejection listing the `expected` media types when it has none.
+    pub(crate) fn from_request_headers(headers: &HeaderMap, expected: impl FnOnce() -> Vec<&'static str>) -> Result<Self, JsonOrProtobufRejection> {
+        match MediaType::from_content_type(headers) {
+            Some(media_type) => Ok(media_type?),
+            None => Err(JsonOrProtobufRejection::MissingContentType { expected: expected() }),
+        }
+    }
+
+    pub fn type_(&self) -> &str {
+        self.essence
+            .split_once('/')
+            .map(|(type_, _)| type_)
+            .unwrap_or(&self.essence)
+    }
+
+    /// The type and subtype without parameters, e.g. `application/json`.
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+
+    pub fn subtype(&self) -> &str {
+        self.essence
+            .split_once('/')
+            .map(|(_, subtype)| subtype)
+            .unwrap_or_default()
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn split_off_params(&mut self, name: &str) -> Vec<(String, String)> {
+        match self.params.iter().position(|(param, _)| param == name) {
+            Some(index) => self.params.split_off(index),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    /// The fully qualified protobuf message name from the `messageType` parameter, or the older
+    /// `proto` parameter.
+    pub fn message_type(&self) -> Option<&str> {
+        self.param("messageType").or_else(|| self.param("proto"))
+    }
+}
+
+impl FromStr for MediaType {
+    type Err = ContentTypeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value).ok_or_else(|| ContentTypeError(value.to_string()))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for MediaType
+where
+    S: Send + Sync
+{
+    type Rejection = JsonOrProtobufRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        MediaType::from_request_headers(&parts.headers, Vec::new)
+    }
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.essence)?;
+
+        for (name, value) in &self.params {
+            if is_token(value) {
+                write!(f, "; {}={}", name, value)?;
+            } else {
+                write!(f, "; {}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == separator {
+            parts.push(&value[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> Option<String> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return is_token(value).then(|| value.to_string());
+    };
+
+    let inner = inner.strip_suffix('"')?;
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next()?),
+            '"' => return None,
+            c => unquoted.push(c),
+        }
+    }
+
+    Some(unquoted)
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowercases_the_type_and_parameter_names() {
+        let media_type = MediaType::parse("Application/JSON; Charset=UTF-8").unwrap();
+
+        assert_eq!(media_type.type_(), "application");
+        assert_eq!(media_type.subtype(), "json");
+        assert_eq!(media_type.params().collect::<Vec<_>>(), [("charset", "UTF-8")]);
+        assert_eq!(media_type.param("CHARSET"), Some("UTF-8"));
+    }
+
+    #[test]
+    fn separators_inside_quotes_stay_in_the_value() {
+        let media_type = MediaType::parse(r#"application/x-protobuf; messageType="a.B;c=d"; delimited=true"#).unwrap();
+
+        assert_eq!(media_type.param("messageType"), Some("a.B;c=d"));
+        assert_eq!(media_type.param("delimited"), Some("true"));
+    }
+
+    #[test]
+    fn unescapes_quoted_values_and_escapes_them_again() {
+        let media_type = MediaType::parse(r#"text/plain; a="x\"y\\z"; b="token""#).unwrap();
+
+        assert_eq!(media_type.param("a"), Some(r#"x"y\z"#));
+        assert_eq!(media_type.param("b"), Some("token"));
+        assert_eq!(media_type.to_string(), r#"text/plain; a="x\"y\\z"; b=token"#);
+    }
+
+    #[test]
+    fn skips_empty_parameters() {
+        let media_type = MediaType::parse("application/json;; charset=utf-8;").unwrap();
+
+        assert_eq!(media_type.params().count(), 1);
+    }
+
+    #[test]
+    fn refuses_invalid_tokens() {
+        let invalid = [
+            "",
+            "application",
+            "application/",
+            "/json",
+            "application/js on",
+            "application/json/x",
+            "application/json; charset",
+            "application/json; =utf-8",
+            "application/json; char set=utf-8",
+            "application/json; charset=utf 8",
+            r#"application/json; charset="utf-8"#,
+            r#"application/json; charset="utf"-8""#,
+        ];
+
+        for value in invalid {
+            assert_eq!(MediaType::parse(value), None, "{value}");
+        }
+    }
+}
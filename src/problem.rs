@@ -0,0 +1,149 @@
+use std::{error::Error, fmt::{self, Display}};
+
+use axum::{http::{header::{ACCEPT_ENCODING, CONTENT_TYPE}, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+use prost::{Message, Name};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "cbor")]
+use crate::CborCodec;
+#[cfg(feature = "msgpack")]
+use crate::MsgPackCodec;
+use crate::{codec, protobuf_content_type_for, Encode, Encoding, Format, JsonCodec, JsonOrProtobufRejection, ProtobufCodec};
+
+pub const CONTENT_TYPE_PROBLEM_JSON: &str = "application/problem+json";
+
+/// An RFC 9457 problem details document.
+///
+/// The protobuf encoding is the message `json_or_protobuf.Problem` with fields `type = 1`,
+/// `title = 2`, `status = 3`, `detail = 4` and `instance = 5`.
+#[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Problem {
+    #[prost(string, tag = "1")]
+    #[serde(rename = "type")]
+    pub r#type: String,
+    #[prost(string, tag = "2")]
+    pub title: String,
+    #[prost(uint32, tag = "3")]
+    pub status: u32,
+    #[prost(string, tag = "4")]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+    #[prost(string, tag = "5")]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub instance: String,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            r#type: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16().into(),
+            detail: detail.into(),
+            instance: String::new(),
+        }
+    }
+}
+
+impl Name for Problem {
+    const NAME: &'static str = "Problem";
+    const PACKAGE: &'static str = "json_or_protobuf";
+}
+
+/// A [`JsonOrProtobufRejection`] rendered as a [`Problem`] in the format negotiated from the
+/// request's `Accept` header.
+#[derive(Debug)]
+pub struct ProblemRejection {
+    rejection: JsonOrProtobufRejection,
+    format: Format,
+    negotiated: bool,
+}
+
+impl ProblemRejection {
+    pub fn new(rejection: JsonOrProtobufRejection, format: Format) -> Self {
+        Self { rejection, format, negotiated: false }
+    }
+
+    // Problems are rendered in whichever format the client accepts, defaulting to `fallback`, so
+    // their responses vary with `Accept`.
+    pub(crate) fn negotiate(headers: &HeaderMap, fallback: Format) -> impl FnOnce(JsonOrProtobufRejection) -> Self {
+        let format = Format::from_accept_header_for_problems(headers).unwrap_or(fallback);
+
+        move |rejection| Self { rejection, format, negotiated: true }
+    }
+
+    pub fn rejection(&self) -> &JsonOrProtobufRejection {
+        &self.rejection
+    }
+
+    pub fn into_rejection(self) -> JsonOrProtobufRejection {
+        self.rejection
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn problem(&self) -> Problem {
+        Problem::new(self.rejection.status(), self.rejection.to_string())
+    }
+}
+
+impl Error for ProblemRejection {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.rejection)
+    }
+}
+
+impl Display for ProblemRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.rejection)
+    }
+}
+
+impl IntoResponse for ProblemRejection {
+    fn into_response(self) -> Response {
+        let mut response = problem_response(self.rejection.status(), &self.problem(), self.format);
+
+        // gRPC-Web clients read the error from the trailers alone.
+        #[cfg(feature = "grpc-web")]
+        if self.format == Format::GrpcWeb {
+            return response;
+        }
+
+        if self.negotiated {
+            codec::vary_accept(&mut response);
+        }
+
+        if let JsonOrProtobufRejection::UnsupportedContentEncoding(_) = self.rejection {
+            if let Ok(accept_encoding) = HeaderValue::from_str(&Encoding::accept_encoding()) {
+                response.headers_mut().insert(ACCEPT_ENCODING, accept_encoding);
+            }
+        }
+
+        response
+    }
+}
+
+// Renders `problem` in `format`, tagging the response with the format like successful ones.
+pub(crate) fn problem_response(status: StatusCode, problem: &Problem, format: Format) -> Response {
+    let (content_type, encoded) = match format {
+        Format::Protobuf => (protobuf_content_type_for::<Problem>(), ProtobufCodec::encode(problem)),
+        Format::Json => (CONTENT_TYPE_PROBLEM_JSON.to_string(), JsonCodec::encode(problem)),
+        #[cfg(feature = "msgpack")]
+        Format::MsgPack => (Format::MsgPack.content_type().to_string(), MsgPackCodec::encode(problem)),
+        #[cfg(feature = "cbor")]
+        Format::Cbor => (Format::Cbor.content_type().to_string(), CborCodec::encode(problem)),
+        #[cfg(feature = "grpc-web")]
+        Format::GrpcWeb => return crate::grpc_web::error_response(status, &problem.detail),
+    };
+
+    let mut response = match encoded {
+        Ok(body) => (status, [(CONTENT_TYPE, content_type)], body).into_response(),
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+
+    response.extensions_mut().insert(format);
+    response
+}
@@ -0,0 +1,135 @@
+use std::ops::{Deref, DerefMut};
+
+use prost::{bytes::{Buf, BufMut}, encoding::{DecodeContext, WireType}, DecodeError, Message};
+use prost_reflect::{DynamicMessage, ReflectMessage, SerializeOptions};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Wraps a message so its serde representation follows the canonical proto3 JSON mapping:
+/// lowerCamelCase field names, 64-bit integers as strings, enums by name and the well-known types
+/// such as `Timestamp`, `Duration` and `Any` in their special forms.
+///
+/// The protobuf encoding is unchanged, so `JsonOrProtobuf<Proto3Json<T>>` only differs from
+/// `JsonOrProtobuf<T>` in its JSON arm.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Proto3Json<T>(pub T);
+
+impl<T> Proto3Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Proto3Json<T> {
+    fn from(inner: T) -> Self {
+        Self(inner)
+    }
+}
+
+impl<T> Deref for Proto3Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Proto3Json<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T> Serialize for Proto3Json<T>
+where
+    T: ReflectMessage
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer
+    {
+        self.0
+            .transcode_to_dynamic()
+            .serialize_with_options(serializer, &SerializeOptions::new())
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Proto3Json<T>
+where
+    T: ReflectMessage + Default
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        let message = DynamicMessage::deserialize(T::default().descriptor(), deserializer)?;
+
+        message
+            .transcode_to()
+            .map(Self)
+            .map_err(D::Error::custom)
+    }
+}
+
+impl<T> Message for Proto3Json<T>
+where
+    T: Message
+{
+    fn encode_raw<B>(&self, buf: &mut B)
+    where
+        B: BufMut,
+        Self: Sized
+    {
+        self.0.encode_raw(buf)
+    }
+
+    fn merge_field<B>(&mut self, tag: u32, wire_type: WireType, buf: &mut B, ctx: DecodeContext) -> Result<(), DecodeError>
+    where
+        B: Buf,
+        Self: Sized
+    {
+        self.0.merge_field(tag, wire_type, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.encoded_len()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_reflect::prost_types::{field_descriptor_proto::{Label, Type}, FieldDescriptorProto, UninterpretedOption};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn writes_64_bit_integers_as_strings() {
+        let option = Proto3Json(UninterpretedOption { negative_int_value: Some(-9007199254740993), ..Default::default() });
+
+        assert_eq!(serde_json::to_value(&option).unwrap(), json!({ "negativeIntValue": "-9007199254740993" }));
+
+        let read: Proto3Json<UninterpretedOption> = serde_json::from_value(json!({ "negativeIntValue": "-9007199254740993" })).unwrap();
+
+        assert_eq!(read, option);
+    }
+
+    #[test]
+    fn writes_enums_by_name() {
+        let field = Proto3Json(FieldDescriptorProto {
+            json_name: Some("itemCount".to_string()),
+            label: Some(Label::Repeated.into()),
+            r#type: Some(Type::Int64.into()),
+            ..Default::default()
+        });
+
+        assert_eq!(serde_json::to_value(&field).unwrap(), json!({ "jsonName": "itemCount", "label": "LABEL_REPEATED", "type": "TYPE_INT64" }));
+
+        let read: Proto3Json<FieldDescriptorProto> = serde_json::from_value(json!({ "jsonName": "itemCount", "label": "LABEL_REPEATED", "type": "TYPE_INT64" })).unwrap();
+
+        assert_eq!(read, field);
+    }
+}
@@ -0,0 +1,263 @@
+use std::{error::Error, fmt::{self, Display}, io};
+
+use axum::{extract::rejection::{BytesRejection, JsonRejection}, http::StatusCode, response::{IntoResponse, Response}};
+use prost::DecodeError;
+
+use crate::{codec, problem, ContentTypeError, Encoding, Format, MediaType, Problem, ProblemRejection};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum JsonOrProtobufRejection {
+    /// A body without a `Content-Type`, listing the media types the extractor reads.
+    MissingContentType { expected: Vec<&'static str> },
+    InvalidContentType(ContentTypeError),
+    /// A `Content-Type` the extractor can't read, listing those it can.
+    UnsupportedMediaType { media_type: MediaType, expected: Vec<&'static str> },
+    UnsupportedCharset(MediaType),
+    BytesRejection(BytesRejection),
+    /// A body larger than the [`BodyLimits`](crate::BodyLimits) for its format.
+    BodyTooLarge { limit: usize },
+    BodyReadError(axum::Error),
+    /// A streamed message larger than the limit for its format.
+    MessageTooLarge { limit: usize },
+    /// A stream whose body ended part way through a message.
+    TruncatedMessage,
+    /// A `Content-Encoding` that is unknown or whose cargo feature is disabled.
+    UnsupportedContentEncoding(String),
+    DecompressionError(io::Error),
+    DecompressedTooLarge { limit: usize },
+    JsonRejection(JsonRejection),
+    ProtobufDecodeError(DecodeError),
+    #[cfg(feature = "msgpack")]
+    MsgPackDecodeError(rmp_serde::decode::Error),
+    #[cfg(feature = "cbor")]
+    CborDecodeError(ciborium::de::Error<std::io::Error>),
+    #[cfg(feature = "grpc-web")]
+    GrpcWebFrameError(&'static str),
+    /// A body that a custom [`Codec`](crate::Codec) failed to decode.
+    CodecError(axum::Error),
+}
+
+impl JsonOrProtobufRejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::MissingContentType { .. }
+            | Self::InvalidContentType(_)
+            | Self::UnsupportedMediaType { .. }
+            | Self::UnsupportedCharset(_)
+            | Self::UnsupportedContentEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::DecompressionError(_) | Self::BodyReadError(_) | Self::TruncatedMessage => StatusCode::BAD_REQUEST,
+            #[cfg(feature = "grpc-web")]
+            Self::GrpcWebFrameError(_) => StatusCode::BAD_REQUEST,
+            Self::BodyTooLarge { .. } | Self::DecompressedTooLarge { .. } | Self::MessageTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::BytesRejection(inner) => inner.status(),
+            Self::JsonRejection(inner) => inner.status(),
+            Self::ProtobufDecodeError(_) | Self::CodecError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            // Mirrors the JSON split between malformed input and well-formed data of the wrong shape.
+            #[cfg(feature = "msgpack")]
+            Self::MsgPackDecodeError(inner) => match inner {
+                rmp_serde::decode::Error::InvalidMarkerRead(_)
+                | rmp_serde::decode::Error::InvalidDataRead(_)
+                | rmp_serde::decode::Error::Utf8Error(_)
+                | rmp_serde::decode::Error::DepthLimitExceeded => StatusCode::BAD_REQUEST,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            },
+            #[cfg(feature = "cbor")]
+            Self::CborDecodeError(inner) => match inner {
+                ciborium::de::Error::Semantic(..) => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::BAD_REQUEST,
+            },
+        }
+    }
+}
+
+impl Error for JsonOrProtobufRejection {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidContentType(inner) => Some(inner),
+            Self::BytesRejection(inner) => Some(inner),
+            Self::BodyReadError(inner) => Some(inner),
+            Self::DecompressionError(inner) => Some(inner),
+            Self::JsonRejection(inner) => Some(inner),
+            Self::ProtobufDecodeError(inner) => Some(inner),
+            Self::CodecError(inner) => Some(inner),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPackDecodeError(inner) => Some(inner),
+            #[cfg(feature = "cbor")]
+            Self::CborDecodeError(inner) => Some(inner),
+            _ => None,
+        }
+    }
+}
+
+impl Display for JsonOrProtobufRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingContentType { expected } => write!(f, "Missing Content-Type{}", Expected(expected)),
+            Self::InvalidContentType(inner) => write!(f, "{}", inner),
+            Self::UnsupportedMediaType { media_type, expected } => write!(f, "Unsupported Content-Type {}{}", media_type, Expected(expected)),
+            Self::UnsupportedCharset(media_type) => write!(f, "Unsupported charset in Content-Type {}", media_type),
+            Self::BytesRejection(inner) => write!(f, "{}", inner.body_text()),
+            Self::BodyTooLarge { limit } => write!(f, "Body exceeds the limit of {} bytes", limit),
+            Self::BodyReadError(inner) => write!(f, "Failed to read the body: {}", inner),
+            Self::MessageTooLarge { limit } => write!(f, "Message exceeds the limit of {} bytes", limit),
+            Self::TruncatedMessage => write!(f, "Body ended part way through a message"),
+            Self::UnsupportedContentEncoding(encoding) => write!(f, "Unsupported Content-Encoding {}, expected one of: {}", encoding, Encoding::accept_encoding()),
+            Self::DecompressionError(inner) => write!(f, "Failed to decompress the body: {}", inner),
+            Self::DecompressedTooLarge { limit } => write!(f, "Decompressed body exceeds the limit of {} bytes", limit),
+            Self::JsonRejection(inner) => write!(f, "{}", inner.body_text()),
+            Self::ProtobufDecodeError(inner) => write!(f, "Failed to decode the protobuf body: {}", inner),
+            Self::CodecError(inner) => write!(f, "Failed to decode the body: {}", inner),
+            #[cfg(feature = "msgpack")]
+            Self::MsgPackDecodeError(inner) => write!(f, "Failed to decode the MessagePack body: {}", inner),
+            #[cfg(feature = "cbor")]
+            Self::CborDecodeError(inner) => write!(f, "Failed to decode the CBOR body: {}", inner),
+            #[cfg(feature = "grpc-web")]
+            Self::GrpcWebFrameError(reason) => write!(f, "Invalid gRPC-Web frame: {}", reason),
+        }
+    }
+}
+
+// Lists the expected media types after a rejection message, if there are any.
+struct Expected<'a>(&'a [&'static str]);
+
+impl Display for Expected<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            write!(f, ", expected one of: {}", self.0.join(", "))
+        }
+    }
+}
+
+impl IntoResponse for JsonOrProtobufRejection {
+    fn into_response(self) -> Response {
+        ProblemRejection::new(self, Format::Json).into_response()
+    }
+}
+
+impl From<ContentTypeError> for JsonOrProtobufRejection {
+    fn from(inner: ContentTypeError) -> Self {
+        Self::InvalidContentType(inner)
+    }
+}
+
+impl From<BytesRejection> for JsonOrProtobufRejection {
+    fn from(inner: BytesRejection) -> Self {
+        Self::BytesRejection(inner)
+    }
+}
+
+impl From<JsonRejection> for JsonOrProtobufRejection {
+    fn from(inner: JsonRejection) -> Self {
+        Self::JsonRejection(inner)
+    }
+}
+
+impl From<DecodeError> for JsonOrProtobufRejection {
+    fn from(inner: DecodeError) -> Self {
+        Self::ProtobufDecodeError(inner)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::decode::Error> for JsonOrProtobufRejection {
+    fn from(inner: rmp_serde::decode::Error) -> Self {
+        Self::MsgPackDecodeError(inner)
+    }
+}
+
+#[derive(Debug)]
+pub struct NotAcceptable {
+    supported: Vec<&'static str>,
+}
+
+impl NotAcceptable {
+    pub(crate) fn new(supported: Vec<&'static str>) -> Self {
+        Self { supported }
+    }
+
+    pub fn supported_media_types(&self) -> &[&'static str] {
+        &self.supported
+    }
+}
+
+impl Error for NotAcceptable {}
+
+impl Display for NotAcceptable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "None of the supported media types are acceptable: {}", self.supported_media_types().join(", "))
+    }
+}
+
+// Rendered as a JSON problem, the format of last resort when nothing the client accepts is on offer.
+impl IntoResponse for NotAcceptable {
+    fn into_response(self) -> Response {
+        let problem = Problem::new(StatusCode::NOT_ACCEPTABLE, self.to_string());
+        let mut response = problem::problem_response(StatusCode::NOT_ACCEPTABLE, &problem, Format::Json);
+
+        codec::vary_accept(&mut response);
+        response
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<ciborium::de::Error<std::io::Error>> for JsonOrProtobufRejection {
+    fn from(inner: ciborium::de::Error<std::io::Error>) -> Self {
+        Self::CborDecodeError(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Json;
+    use prost::Message;
+
+    use super::*;
+
+    #[test]
+    fn maps_each_rejection_to_its_status() {
+        let media_type = || MediaType::parse("text/plain").unwrap();
+        let syntax_error = Json::<u32>::from_bytes(b"one").unwrap_err();
+        let data_error = Json::<u32>::from_bytes(b"\"1\"").unwrap_err();
+
+        #[cfg_attr(not(any(feature = "msgpack", feature = "cbor", feature = "grpc-web")), allow(unused_mut))]
+        let mut cases = vec![
+            (JsonOrProtobufRejection::MissingContentType { expected: Vec::new() }, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (JsonOrProtobufRejection::InvalidContentType(ContentTypeError("json".to_string())), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (JsonOrProtobufRejection::UnsupportedMediaType { media_type: media_type(), expected: Vec::new() }, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (JsonOrProtobufRejection::UnsupportedCharset(media_type()), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (JsonOrProtobufRejection::UnsupportedContentEncoding("compress".to_string()), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (JsonOrProtobufRejection::BodyTooLarge { limit: 1 }, StatusCode::PAYLOAD_TOO_LARGE),
+            (JsonOrProtobufRejection::MessageTooLarge { limit: 1 }, StatusCode::PAYLOAD_TOO_LARGE),
+            (JsonOrProtobufRejection::DecompressedTooLarge { limit: 1 }, StatusCode::PAYLOAD_TOO_LARGE),
+            (JsonOrProtobufRejection::BodyReadError(axum::Error::new(io::Error::other("reset"))), StatusCode::BAD_REQUEST),
+            (JsonOrProtobufRejection::TruncatedMessage, StatusCode::BAD_REQUEST),
+            (JsonOrProtobufRejection::DecompressionError(io::Error::other("corrupt")), StatusCode::BAD_REQUEST),
+            (JsonOrProtobufRejection::JsonRejection(syntax_error), StatusCode::BAD_REQUEST),
+            (JsonOrProtobufRejection::JsonRejection(data_error), StatusCode::UNPROCESSABLE_ENTITY),
+            (JsonOrProtobufRejection::ProtobufDecodeError(u32::decode(&[0xff][..]).unwrap_err()), StatusCode::UNPROCESSABLE_ENTITY),
+            (JsonOrProtobufRejection::CodecError(axum::Error::new(io::Error::other("invalid"))), StatusCode::UNPROCESSABLE_ENTITY),
+        ];
+
+        #[cfg(feature = "msgpack")]
+        cases.extend([
+            (JsonOrProtobufRejection::MsgPackDecodeError(rmp_serde::from_slice::<String>(&[]).unwrap_err()), StatusCode::BAD_REQUEST),
+            (JsonOrProtobufRejection::MsgPackDecodeError(rmp_serde::from_slice::<String>(&[0x01]).unwrap_err()), StatusCode::UNPROCESSABLE_ENTITY),
+        ]);
+
+        #[cfg(feature = "cbor")]
+        cases.extend([
+            (JsonOrProtobufRejection::CborDecodeError(ciborium::from_reader::<String, _>(&[][..]).unwrap_err()), StatusCode::BAD_REQUEST),
+            (JsonOrProtobufRejection::CborDecodeError(ciborium::from_reader::<String, _>(&[0x01][..]).unwrap_err()), StatusCode::UNPROCESSABLE_ENTITY),
+        ]);
+
+        #[cfg(feature = "grpc-web")]
+        cases.push((JsonOrProtobufRejection::GrpcWebFrameError("short frame"), StatusCode::BAD_REQUEST));
+
+        for (rejection, status) in cases {
+            assert_eq!(rejection.status(), status, "{rejection:?}");
+        }
+    }
+}
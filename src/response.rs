@@ -0,0 +1,199 @@
+use std::{borrow::Cow, ops::Deref};
+
+use axum::{body::Bytes, http::{header::{CACHE_CONTROL, ETAG, LOCATION}, HeaderMap, HeaderName, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+
+use crate::{codec, AcceptPolicy, EncodeBody, JsonOrProtobuf, NotAcceptable};
+
+/// A negotiated body with the status and headers to send it with.
+///
+/// Wraps any of [`JsonOrProtobuf`], [`Negotiated`](crate::Negotiated) or
+/// [`Compressed`](crate::Compressed), keeping their encoding. The status and headers only apply
+/// when the body encodes successfully. Like them, the response carries the [`Format`](crate::Format)
+/// of the body as an extension.
+pub struct NegotiatedResponse<B> {
+    body: B,
+    status: StatusCode,
+    headers: HeaderMap,
+    // Names whose values replace those of the body's response rather than adding to them.
+    replaced: Vec<HeaderName>,
+}
+
+impl<B> NegotiatedResponse<B> {
+    pub fn new(body: B) -> Self {
+        Self {
+            body,
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            replaced: Vec::new(),
+        }
+    }
+
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Sets a header, replacing any previous values of the same name, including those of the body
+    /// such as its `Content-Type`.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        if !self.replaced.contains(&name) {
+            self.replaced.push(name.clone());
+        }
+
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Appends a header, keeping any previous values of the same name.
+    pub fn append_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn location(self, location: HeaderValue) -> Self {
+        self.header(LOCATION, location)
+    }
+
+    pub fn etag(self, etag: HeaderValue) -> Self {
+        self.header(ETAG, etag)
+    }
+
+    pub fn cache_control(self, cache_control: HeaderValue) -> Self {
+        self.header(CACHE_CONTROL, cache_control)
+    }
+
+    /// The headers to send, which are appended to those of the body.
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &B {
+        &self.body
+    }
+
+    pub fn into_inner(self) -> B {
+        self.body
+    }
+}
+
+impl<B> From<B> for NegotiatedResponse<B> {
+    fn from(body: B) -> Self {
+        Self::new(body)
+    }
+}
+
+impl<B> IntoResponse for NegotiatedResponse<B>
+where
+    B: IntoResponse
+{
+    fn into_response(self) -> Response {
+        let mut response = self.body.into_response();
+
+        if !response.status().is_success() {
+            return response;
+        }
+
+        *response.status_mut() = self.status;
+
+        for name in &self.replaced {
+            response.headers_mut().remove(name);
+        }
+
+        let mut name = None;
+
+        // `HeaderMap::into_iter` only names the first of several values for the same header.
+        for (next_name, value) in self.headers {
+            if next_name.is_some() {
+                name = next_name;
+            }
+
+            if let Some(name) = &name {
+                response.headers_mut().append(name, value);
+            }
+        }
+
+        response
+    }
+}
+
+/// A body whose format was negotiated from the `Accept` header, so that its responses carry
+/// `Vary: Accept`.
+///
+/// [`JsonOrProtobuf`] can't record how its format was chosen, so wrap the result of
+/// [`JsonOrProtobuf::reply`] or [`ResponseFormat::respond`](crate::ResponseFormat::respond) in it,
+/// or use its constructors mirroring those of [`JsonOrProtobuf`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FromAccept<B>(pub B);
+
+impl<B> FromAccept<B> {
+    pub fn into_inner(self) -> B {
+        self.0
+    }
+}
+
+impl<T> FromAccept<JsonOrProtobuf<T>> {
+    /// Like [`JsonOrProtobuf::from_accept_header`].
+    pub fn from_accept_header(body: T, headers: &HeaderMap) -> Self {
+        Self(JsonOrProtobuf::from_accept_header(body, headers))
+    }
+
+    /// Like [`JsonOrProtobuf::try_from_accept_header`].
+    pub fn try_from_accept_header(body: T, headers: &HeaderMap) -> Result<Self, NotAcceptable> {
+        JsonOrProtobuf::try_from_accept_header(body, headers).map(Self)
+    }
+
+    /// Like [`JsonOrProtobuf::negotiate`].
+    pub fn negotiate(body: T, headers: &HeaderMap, policy: AcceptPolicy) -> Result<Self, NotAcceptable> {
+        JsonOrProtobuf::negotiate(body, headers, policy).map(Self)
+    }
+}
+
+impl<B> Deref for FromAccept<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.0
+    }
+}
+
+impl<B> EncodeBody for FromAccept<B>
+where
+    B: EncodeBody
+{
+    fn encode_body(&self) -> (Cow<'static, str>, Result<Bytes, axum::Error>) {
+        self.0.encode_body()
+    }
+
+    fn negotiated(&self) -> bool {
+        true
+    }
+}
+
+impl<B> IntoResponse for FromAccept<B>
+where
+    B: EncodeBody
+{
+    fn into_response(self) -> Response {
+        codec::body_response(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{CONTENT_TYPE, VARY};
+
+    use super::*;
+
+    #[test]
+    fn header_replaces_and_append_header_adds() {
+        let body = ([(CONTENT_TYPE, "application/json"), (VARY, "accept")], "{}");
+
+        let response = NegotiatedResponse::new(body)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/vnd.item+json"))
+            .append_header(VARY, HeaderValue::from_static("origin"))
+            .into_response();
+
+        assert_eq!(response.headers().get_all(CONTENT_TYPE).iter().collect::<Vec<_>>(), ["application/vnd.item+json"]);
+        assert_eq!(response.headers().get_all(VARY).iter().collect::<Vec<_>>(), ["accept", "origin"]);
+    }
+}
@@ -0,0 +1,39 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+
+use crate::{AcceptPolicy, Format, JsonOrProtobuf, JsonOrProtobufConfig, NotAcceptable};
+
+/// The response format negotiated from the request's `Accept` header, following the
+/// [`JsonOrProtobufConfig`] of the request if any.
+///
+/// Refuses with [`NotAcceptable`] only when the configuration is strict about `Accept`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseFormat(pub Format);
+
+impl ResponseFormat {
+    pub fn format(self) -> Format {
+        self.0
+    }
+
+    /// Wraps `body` in the negotiated format. Wrap the result in [`FromAccept`](crate::FromAccept)
+    /// for its response to carry `Vary: Accept`.
+    pub fn respond<T>(self, body: T) -> JsonOrProtobuf<T> {
+        JsonOrProtobuf::from_format(body, self.0)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ResponseFormat
+where
+    S: Send + Sync
+{
+    type Rejection = NotAcceptable;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let policy = parts
+            .extensions
+            .get::<JsonOrProtobufConfig>()
+            .map_or_else(AcceptPolicy::default, JsonOrProtobufConfig::accept_policy);
+
+        Format::negotiate(&parts.headers, policy).map(Self)
+    }
+}
@@ -0,0 +1,173 @@
+use std::{pin::Pin, time::Duration};
+
+use axum::{http::HeaderMap, response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response}};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{Stream, StreamExt};
+use prost::Message;
+use serde::Serialize;
+
+use crate::{accept, codec, AcceptPolicy, EncodeBody, Format, JsonOrProtobuf, NotAcceptable};
+
+/// A server-sent event carrying a `T`, with the optional `id`, `event` and `retry` fields.
+pub struct SseEvent<T> {
+    body: T,
+    id: Option<String>,
+    event: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl<T> SseEvent<T> {
+    pub fn new(body: T) -> Self {
+        Self {
+            body,
+            id: None,
+            event: None,
+            retry: None,
+        }
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl<T> SseEvent<T>
+where
+    T: Serialize + Message
+{
+    // JSON is sent as is, binary formats are base64 encoded to fit in the text event stream.
+    fn into_event(self, format: Format) -> Result<Event, axum::Error> {
+        // `Event` panics on line breaks in these fields, which would otherwise end the event early.
+        let has_line_break = |field: &Option<String>| field
+            .as_deref()
+            .is_some_and(|field| field.contains(['\n', '\r']));
+
+        if has_line_break(&self.id) || has_line_break(&self.event) {
+            return Err(axum::Error::new("SSE id and event fields cannot contain line breaks"));
+        }
+
+        let (_, encoded) = JsonOrProtobuf::from_format(self.body, format).encode_body();
+        let encoded = encoded?;
+
+        let data = match format {
+            Format::Json => String::from_utf8(encoded.to_vec()).map_err(axum::Error::new)?,
+            _ => STANDARD.encode(&encoded),
+        };
+
+        let mut event = Event::default().data(data);
+
+        if let Some(id) = self.id {
+            event = event.id(id);
+        }
+
+        if let Some(name) = self.event {
+            event = event.event(name);
+        }
+
+        if let Some(retry) = self.retry {
+            event = event.retry(retry);
+        }
+
+        Ok(event)
+    }
+}
+
+// The ranges left once `text/event-stream` is dropped, which are the ones that concern payloads.
+fn payload_accept(headers: &HeaderMap) -> HeaderMap {
+    accept::without(headers, "text/event-stream")
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, axum::Error>> + Send>>;
+
+/// A server-sent event stream whose payloads are JSON, or base64 encoded protobuf when the client
+/// negotiated protobuf, following the same rules as [`JsonOrProtobuf::from_accept_header`].
+///
+/// `text/event-stream` in `Accept` says nothing about the payloads and is ignored, so a client asks
+/// for protobuf with e.g. `Accept: text/event-stream, application/x-protobuf`. Browsers'
+/// `EventSource` can't set headers and gets JSON; to serve it protobuf, take the format from the
+/// URL and use [`SseResponse::new`].
+///
+/// An event that fails to encode ends the stream.
+pub struct SseResponse {
+    format: Format,
+    events: EventStream,
+    keep_alive: Option<KeepAlive>,
+    negotiated: bool,
+}
+
+impl SseResponse {
+    pub fn new<S, T>(events: S, format: Format) -> Self
+    where
+        S: Stream<Item = SseEvent<T>> + Send + 'static,
+        T: Serialize + Message + 'static
+    {
+        Self {
+            format,
+            events: Box::pin(events.map(move |event| event.into_event(format))),
+            keep_alive: None,
+            negotiated: false,
+        }
+    }
+
+    /// Picks the payload format from the `Accept` header, falling back to JSON when no supported
+    /// format is acceptable.
+    pub fn from_accept_header<S, T>(events: S, headers: &HeaderMap) -> Self
+    where
+        S: Stream<Item = SseEvent<T>> + Send + 'static,
+        T: Serialize + Message + 'static
+    {
+        let format = Format::from_accept_header(&payload_accept(headers)).unwrap_or(Format::Json);
+
+        Self { negotiated: true, ..Self::new(events, format) }
+    }
+
+    /// Like [`SseResponse::from_accept_header`], but refuses with [`NotAcceptable`] instead of
+    /// falling back to JSON.
+    pub fn try_from_accept_header<S, T>(events: S, headers: &HeaderMap) -> Result<Self, NotAcceptable>
+    where
+        S: Stream<Item = SseEvent<T>> + Send + 'static,
+        T: Serialize + Message + 'static
+    {
+        let format = Format::negotiate(&payload_accept(headers), AcceptPolicy::Strict)?;
+
+        Ok(Self { negotiated: true, ..Self::new(events, format) })
+    }
+
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+impl IntoResponse for SseResponse {
+    fn into_response(self) -> Response {
+        let mut sse = Sse::new(self.events);
+
+        if let Some(keep_alive) = self.keep_alive {
+            sse = sse.keep_alive(keep_alive);
+        }
+
+        let mut response = sse.into_response();
+
+        if self.negotiated {
+            codec::vary_accept(&mut response);
+        }
+
+        response
+    }
+}
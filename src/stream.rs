@@ -0,0 +1,610 @@
+use std::{error::Error, fmt::{self, Display}, marker::PhantomData, pin::Pin, task::{Context, Poll}};
+
+use axum::{async_trait, body::{Body, Bytes}, extract::{FromRequest, Request}, http::{header::CONTENT_TYPE, HeaderMap, HeaderValue}, response::{IntoResponse, Response}, BoxError};
+use bytes::{Buf, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{accept, body, codec, compression, Decode, Format, JsonCodec, JsonOrProtobufConfig, JsonOrProtobufRejection, MediaType, NotAcceptable, ProblemRejection, ProtobufCodec, CONTENT_TYPE_NDJSON, DEFAULT_DECOMPRESSED_LIMIT, PROTOBUF_CONTENT_TYPES};
+
+/// The framing of a stream of messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamFormat {
+    /// Newline delimited JSON, one document per line.
+    Json,
+    /// Protobuf messages each prefixed with their varint encoded length.
+    Protobuf,
+}
+
+impl StreamFormat {
+    pub const ALL: &'static [StreamFormat] = &[StreamFormat::Json, StreamFormat::Protobuf];
+
+    /// The `Content-Type` of streams in this format, protobuf streams carrying `delimited=true`.
+    pub fn content_type(self) -> String {
+        match self {
+            StreamFormat::Json => CONTENT_TYPE_NDJSON.to_string(),
+            StreamFormat::Protobuf => format!("{}; delimited=true", Format::Protobuf.content_type()),
+        }
+    }
+
+    /// Every media type recognised as this format.
+    pub fn media_types(self) -> &'static [&'static str] {
+        match self {
+            StreamFormat::Json => &[CONTENT_TYPE_NDJSON],
+            StreamFormat::Protobuf => &PROTOBUF_CONTENT_TYPES,
+        }
+    }
+
+    /// Negotiates a stream format from the `Accept` header, treating a missing header as accepting
+    /// either. Protobuf is left out when the client refuses the type it would be sent as, see
+    /// [`Format::content_type`].
+    pub fn from_accept_header(headers: &HeaderMap) -> Option<Self> {
+        let protobuf_refused = accept::refuses(headers, Format::Protobuf.content_type());
+
+        let media_types: Vec<_> = Self::ALL
+            .iter()
+            .map(|format| match format {
+                StreamFormat::Protobuf if protobuf_refused => &[],
+                _ => format.media_types(),
+            })
+            .collect();
+
+        accept::negotiate_groups(headers, &media_types).map(|index| Self::ALL[index])
+    }
+
+    pub fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|format| format.media_types().contains(&media_type.essence()))
+    }
+
+    fn encode<T>(self, item: &T) -> Result<Bytes, axum::Error>
+    where
+        T: Serialize + Message
+    {
+        match self {
+            StreamFormat::Json => {
+                let mut line = serde_json::to_vec(item).map_err(axum::Error::new)?;
+                line.push(b'\n');
+                Ok(line.into())
+            },
+            StreamFormat::Protobuf => Ok(item.encode_length_delimited_to_vec().into()),
+        }
+    }
+}
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, axum::Error>> + Send>>;
+
+/// A response streaming NDJSON or length delimited protobuf, encoding each message as the client
+/// reads it so the whole payload is never held in memory.
+///
+/// The stream is only polled as fast as the body is written, which gives backpressure in both
+/// formats. When a message fails to encode, or the stream yields an error, the response is aborted
+/// after the messages already sent, so clients see a truncated body rather than a clean end.
+pub struct StreamResponse {
+    format: StreamFormat,
+    body: ByteStream,
+    negotiated: bool,
+}
+
+impl StreamResponse {
+    pub fn new<S, T>(stream: S, format: StreamFormat) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+        T: Serialize + Message + 'static
+    {
+        Self::try_new(stream.map(Ok::<_, BoxError>), format)
+    }
+
+    /// Like [`StreamResponse::new`], for streams that can fail part way through.
+    pub fn try_new<S, T, E>(stream: S, format: StreamFormat) -> Self
+    where
+        S: Stream<Item = Result<T, E>> + Send + 'static,
+        T: Serialize + Message + 'static,
+        E: Into<BoxError> + 'static
+    {
+        // Nothing is polled after the first error, which ends the body.
+        let body = stream
+            .map(move |item| item.map_err(axum::Error::new).and_then(|item| format.encode(&item)))
+            .scan(false, |failed, item| {
+                let item = (!*failed).then(|| {
+                    *failed = item.is_err();
+                    item
+                });
+
+                async move { item }
+            });
+
+        Self {
+            format,
+            body: Box::pin(body),
+            negotiated: false,
+        }
+    }
+
+    /// Picks the format from the `Accept` header, falling back to NDJSON when neither is acceptable.
+    pub fn from_accept_header<S, T>(stream: S, headers: &HeaderMap) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+        T: Serialize + Message + 'static
+    {
+        let format = StreamFormat::from_accept_header(headers).unwrap_or(StreamFormat::Json);
+
+        Self { negotiated: true, ..Self::new(stream, format) }
+    }
+
+    /// Like [`StreamResponse::from_accept_header`], but refuses with [`NotAcceptable`] instead of
+    /// falling back to NDJSON.
+    pub fn try_from_accept_header<S, T>(stream: S, headers: &HeaderMap) -> Result<Self, NotAcceptable>
+    where
+        S: Stream<Item = T> + Send + 'static,
+        T: Serialize + Message + 'static
+    {
+        match StreamFormat::from_accept_header(headers) {
+            Some(format) => Ok(Self { negotiated: true, ..Self::new(stream, format) }),
+            None => Err(NotAcceptable::new(StreamFormat::ALL.iter().flat_map(|format| format.media_types().iter().copied()).collect())),
+        }
+    }
+
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+}
+
+impl IntoResponse for StreamResponse {
+    fn into_response(self) -> Response {
+        let content_type = self.format.content_type();
+
+        let mut response = (
+            [(CONTENT_TYPE, HeaderValue::from_str(&content_type).expect("stream content types are valid header values"))],
+            Body::from_stream(self.body),
+        ).into_response();
+
+        response.extensions_mut().insert(self.format.as_format());
+
+        if self.negotiated {
+            codec::vary_accept(&mut response);
+        }
+
+        response
+    }
+}
+
+/// A failure part way through a [`JsonOrProtobufStream`].
+#[derive(Debug)]
+pub struct StreamError {
+    decoded: usize,
+    rejection: JsonOrProtobufRejection,
+}
+
+impl StreamError {
+    /// How many messages were decoded before the failure.
+    pub fn decoded(&self) -> usize {
+        self.decoded
+    }
+
+    pub fn rejection(&self) -> &JsonOrProtobufRejection {
+        &self.rejection
+    }
+
+    pub fn into_rejection(self) -> JsonOrProtobufRejection {
+        self.rejection
+    }
+}
+
+impl Error for StreamError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.rejection)
+    }
+}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} after {} messages", self.rejection, self.decoded)
+    }
+}
+
+impl IntoResponse for StreamError {
+    fn into_response(self) -> Response {
+        self.rejection.into_response()
+    }
+}
+
+type MessageStream<T> = Pin<Box<dyn Stream<Item = Result<T, StreamError>> + Send>>;
+
+/// Extracts an NDJSON or length delimited protobuf body as a stream of messages, decoding each one
+/// as it arrives rather than buffering the whole body.
+///
+/// The [`BodyLimits`](crate::BodyLimits) for the format cap each message rather than the body.
+/// Compressed bodies are the exception: they are buffered and decompressed whole, within the same
+/// limits, before being decoded message by message. The stream ends after the first error.
+pub struct JsonOrProtobufStream<T> {
+    format: StreamFormat,
+    messages: MessageStream<T>,
+}
+
+impl<T> JsonOrProtobufStream<T> {
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+}
+
+impl<T> Stream for JsonOrProtobufStream<T> {
+    type Item = Result<T, StreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.as_mut().poll_next(cx)
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonOrProtobufStream<T>
+where
+    T: DeserializeOwned + Message + Default + 'static,
+    S: Send + Sync
+{
+    type Rejection = ProblemRejection;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let config = JsonOrProtobufConfig::of(request.extensions());
+        let reject = ProblemRejection::negotiate(request.headers(), config.default_format);
+
+        Self::extract(request, state, &config)
+            .await
+            .map_err(reject)
+    }
+}
+
+impl<T> JsonOrProtobufStream<T>
+where
+    T: DeserializeOwned + Message + Default + 'static
+{
+    async fn extract<S>(request: Request, state: &S, config: &JsonOrProtobufConfig) -> Result<Self, JsonOrProtobufRejection>
+    where
+        S: Send + Sync
+    {
+        let expected = || {
+            StreamFormat::ALL
+                .iter()
+                .filter(|format| config.accepts(format.as_format()))
+                .flat_map(|format| format.media_types().iter().copied())
+                .collect()
+        };
+
+        let media_type = MediaType::from_request_headers(request.headers(), expected)?;
+
+        let Some(format) = StreamFormat::from_media_type(&media_type).filter(|format| config.accepts(format.as_format())) else {
+            return Err(JsonOrProtobufRejection::UnsupportedMediaType { media_type, expected: expected() });
+        };
+
+        if let Some(charset) = media_type.charset() {
+            if !format.as_format().supports_charset(charset) {
+                return Err(JsonOrProtobufRejection::UnsupportedCharset(media_type));
+            }
+        }
+
+        let limit = body::body_limit(&request, Some(format.as_format())).unwrap_or(DEFAULT_DECOMPRESSED_LIMIT);
+
+        let chunks: ChunkStream = if compression::content_encodings(request.headers())?.is_empty() {
+            Box::pin(request.into_body().into_data_stream())
+        } else {
+            let bytes = body::read_body(request, state, &media_type).await?;
+
+            Box::pin(stream::once(async move { Ok(bytes) }))
+        };
+
+        let decoder = Decoder {
+            chunks,
+            buffer: BytesMut::new(),
+            scanned: 0,
+            format,
+            limit,
+            decoded: 0,
+            finished: false,
+            message: PhantomData,
+        };
+
+        let messages = stream::unfold(decoder, |mut decoder| async move {
+            let message = decoder.next().await?;
+
+            Some((message, decoder))
+        });
+
+        Ok(Self {
+            format,
+            messages: Box::pin(messages),
+        })
+    }
+}
+
+impl StreamFormat {
+    fn as_format(self) -> Format {
+        match self {
+            StreamFormat::Json => Format::Json,
+            StreamFormat::Protobuf => Format::Protobuf,
+        }
+    }
+}
+
+type ChunkStream = Pin<Box<dyn Stream<Item = Result<Bytes, axum::Error>> + Send>>;
+
+struct Decoder<T> {
+    chunks: ChunkStream,
+    buffer: BytesMut,
+    // How much of `buffer` is known not to contain a line break.
+    scanned: usize,
+    format: StreamFormat,
+    limit: usize,
+    decoded: usize,
+    finished: bool,
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T> Decoder<T>
+where
+    T: DeserializeOwned + Message + Default
+{
+    async fn next(&mut self) -> Option<Result<T, StreamError>> {
+        if self.finished {
+            return None;
+        }
+
+        let message = self.next_message().await.transpose()?;
+
+        let message = message.and_then(|bytes| match self.format {
+            StreamFormat::Json => JsonCodec::decode(bytes),
+            StreamFormat::Protobuf => ProtobufCodec::decode(bytes),
+        });
+
+        match message {
+            Ok(message) => {
+                self.decoded += 1;
+                Some(Ok(message))
+            },
+            Err(rejection) => {
+                self.finished = true;
+                Some(Err(StreamError { decoded: self.decoded, rejection }))
+            },
+        }
+    }
+
+    // Reads until the buffer holds a whole message, returning `None` at the end of the body.
+    async fn next_message(&mut self) -> Result<Option<Bytes>, JsonOrProtobufRejection> {
+        let mut end_of_body = false;
+
+        loop {
+            if let Some(message) = self.split_message(end_of_body)? {
+                return Ok(Some(message));
+            }
+
+            if end_of_body {
+                return Ok(None);
+            }
+
+            match self.chunks.next().await {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(err)) => return Err(JsonOrProtobufRejection::BodyReadError(err)),
+                None => end_of_body = true,
+            }
+        }
+    }
+
+    fn split_message(&mut self, end_of_body: bool) -> Result<Option<Bytes>, JsonOrProtobufRejection> {
+        match self.format {
+            StreamFormat::Json => loop {
+                let line = match self.buffer[self.scanned..].iter().position(|byte| *byte == b'\n') {
+                    Some(end) => {
+                        let end = self.scanned + end;
+                        self.scanned = 0;
+                        self.buffer.split_to(end + 1)
+                    },
+                    None if end_of_body && !self.buffer.is_empty() => {
+                        self.scanned = 0;
+                        self.buffer.split()
+                    },
+                    None if self.buffer.len() > self.limit => return Err(JsonOrProtobufRejection::MessageTooLarge { limit: self.limit }),
+                    None => {
+                        self.scanned = self.buffer.len();
+                        return Ok(None);
+                    },
+                };
+
+                if line.len() > self.limit {
+                    return Err(JsonOrProtobufRejection::MessageTooLarge { limit: self.limit });
+                }
+
+                // Blank lines between documents are skipped.
+                if !line.trim_ascii().is_empty() {
+                    return Ok(Some(line.freeze()));
+                }
+            },
+            StreamFormat::Protobuf => {
+                // A varint is at most ten bytes, and complete once a byte has its high bit clear.
+                let delimiter_complete = self.buffer.iter().take(10).any(|byte| byte & 0x80 == 0);
+
+                if !delimiter_complete && self.buffer.len() < 10 {
+                    if end_of_body && !self.buffer.is_empty() {
+                        return Err(JsonOrProtobufRejection::TruncatedMessage);
+                    }
+
+                    return Ok(None);
+                }
+
+                // Count the bytes actually read, as the delimiter may not be minimally encoded.
+                let mut delimiter = &self.buffer[..];
+                let length = prost::decode_length_delimiter(&mut delimiter)?;
+                let delimiter_length = self.buffer.len() - delimiter.remaining();
+
+                if length > self.limit {
+                    return Err(JsonOrProtobufRejection::MessageTooLarge { limit: self.limit });
+                }
+
+                if self.buffer.len() < delimiter_length + length {
+                    if end_of_body {
+                        return Err(JsonOrProtobufRejection::TruncatedMessage);
+                    }
+
+                    return Ok(None);
+                }
+
+                self.buffer.advance(delimiter_length);
+
+                Ok(Some(self.buffer.split_to(length).freeze()))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message, Deserialize)]
+    struct Item {
+        #[prost(string, tag = "1")]
+        name: String,
+    }
+
+    fn item(name: &str) -> Item {
+        Item { name: name.to_string() }
+    }
+
+    fn decode(format: StreamFormat, chunks: &[&[u8]]) -> Vec<Result<Item, StreamError>> {
+        let chunks: Vec<Result<Bytes, axum::Error>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+
+        let mut decoder = Decoder {
+            chunks: Box::pin(stream::iter(chunks)),
+            buffer: BytesMut::new(),
+            scanned: 0,
+            format,
+            limit: 64,
+            decoded: 0,
+            finished: false,
+            message: PhantomData,
+        };
+
+        let mut messages = Vec::new();
+
+        // The chunks are all ready, so the decoder never has to wait.
+        while let Some(message) = decoder.next().now_or_never().expect("decoding does not block") {
+            messages.push(message);
+        }
+
+        messages
+    }
+
+    fn delimited(items: &[Item]) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        for item in items {
+            item.encode_length_delimited(&mut body).unwrap();
+        }
+
+        body
+    }
+
+    #[test]
+    fn splits_json_lines_across_chunks() {
+        let messages = decode(StreamFormat::Json, &[b"{\"na", b"me\":\"a\"}\n\n{\"name\":", b"\"b\"}\r\n{\"name\":\"c\"}"]);
+        let names: Vec<_> = messages.into_iter().map(|message| message.unwrap().name).collect();
+
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn refuses_overlong_json_lines() {
+        let line = format!("{{\"name\":\"{}\"}}", "x".repeat(64));
+        let messages = decode(StreamFormat::Json, &[b"{\"name\":\"a\"}\n", line.as_bytes()]);
+
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[1], Err(StreamError { decoded: 1, rejection: JsonOrProtobufRejection::MessageTooLarge { limit: 64 } })));
+    }
+
+    #[test]
+    fn splits_delimited_messages_across_chunks() {
+        let long = "b".repeat(20);
+        let body = delimited(&[item("a"), item(&long), item("c")]);
+
+        for chunk_size in 1..body.len() {
+            let chunks: Vec<_> = body.chunks(chunk_size).collect();
+            let names: Vec<_> = decode(StreamFormat::Protobuf, &chunks)
+                .into_iter()
+                .map(|message| message.unwrap().name)
+                .collect();
+
+            assert_eq!(names, ["a", long.as_str(), "c"], "chunks of {chunk_size}");
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_delimited_messages() {
+        let body = delimited(&[item("a"), item("b")]);
+
+        for truncated in [&body[..body.len() - 1], &body[..5]] {
+            let messages = decode(StreamFormat::Protobuf, &[truncated]);
+
+            assert!(matches!(messages.last(), Some(Err(StreamError { decoded: 1, rejection: JsonOrProtobufRejection::TruncatedMessage }))));
+        }
+
+        let messages = decode(StreamFormat::Protobuf, &[&[0x80]]);
+
+        assert!(matches!(messages[..], [Err(StreamError { decoded: 0, rejection: JsonOrProtobufRejection::TruncatedMessage })]));
+    }
+
+    #[test]
+    fn reads_non_minimal_length_prefixes() {
+        let mut body = vec![0x85, 0x00];
+        item("abc").encode(&mut body).unwrap();
+        body.extend(delimited(&[item("d")]));
+
+        let names: Vec<_> = decode(StreamFormat::Protobuf, &[&body])
+            .into_iter()
+            .map(|message| message.unwrap().name)
+            .collect();
+
+        assert_eq!(names, ["abc", "d"]);
+    }
+
+    #[test]
+    fn refuses_overlong_length_prefixes() {
+        let messages = decode(StreamFormat::Protobuf, &[&[0xff; 11]]);
+
+        assert!(matches!(messages[..], [Err(StreamError { decoded: 0, rejection: JsonOrProtobufRejection::ProtobufDecodeError(_) })]));
+
+        let messages = decode(StreamFormat::Protobuf, &[&[0x80, 0x01]]);
+
+        assert!(matches!(messages[..], [Err(StreamError { decoded: 0, rejection: JsonOrProtobufRejection::MessageTooLarge { limit: 64 } })]));
+    }
+
+    fn extract(content_type: &'static str, config: JsonOrProtobufConfig) -> Result<JsonOrProtobufStream<Item>, JsonOrProtobufRejection> {
+        let request = Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from("{\"name\":\"a\"}\n"))
+            .unwrap();
+
+        JsonOrProtobufStream::extract(request, &(), &config)
+            .now_or_never()
+            .expect("the body is ready")
+    }
+
+    #[test]
+    fn applies_the_charset_and_format_checks() {
+        assert!(extract("application/x-ndjson; charset=utf-8", JsonOrProtobufConfig::new()).is_ok());
+        assert!(matches!(extract("application/x-ndjson; charset=latin1", JsonOrProtobufConfig::new()), Err(JsonOrProtobufRejection::UnsupportedCharset(_))));
+
+        let protobuf_only = JsonOrProtobufConfig::new().formats(&[Format::Protobuf]);
+
+        assert!(matches!(
+            extract("application/x-ndjson", protobuf_only),
+            Err(JsonOrProtobufRejection::UnsupportedMediaType { expected, .. }) if !expected.contains(&CONTENT_TYPE_NDJSON)
+        ));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{borrow::Cow, future::Future, marker::PhantomData};
+
+use axum::{extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, response::Response};
+use prost::Message as ProtobufMessage;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Decode, EncodeBody, Format, JsonCodec, JsonOrProtobuf, JsonOrProtobufRejection, ProtobufCodec};
+
+/// The WebSocket subprotocol for JSON text frames.
+pub const WS_PROTOCOL_JSON: &str = "json";
+/// The WebSocket subprotocol for protobuf binary frames.
+pub const WS_PROTOCOL_PROTOBUF: &str = "protobuf";
+
+/// A WebSocket sending `T` as JSON text frames or protobuf binary frames, depending on the
+/// subprotocol negotiated at upgrade, and decoding incoming frames by their type.
+pub struct JsonOrProtobufSocket<T> {
+    socket: WebSocket,
+    format: Format,
+    closed: bool,
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonOrProtobufSocket<T> {
+    /// Upgrades the connection offering the [`WS_PROTOCOL_JSON`] and [`WS_PROTOCOL_PROTOBUF`]
+    /// subprotocols, in that order of preference. Clients requesting neither get JSON.
+    pub fn upgrade<C, Fut>(ws: WebSocketUpgrade, callback: C) -> Response
+    where
+        C: FnOnce(Self) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static
+    {
+        ws.protocols([WS_PROTOCOL_JSON, WS_PROTOCOL_PROTOBUF])
+            .on_upgrade(|socket| callback(Self::new(socket)))
+    }
+
+    /// Wraps an upgraded socket, taking the format from its negotiated subprotocol.
+    pub fn new(socket: WebSocket) -> Self {
+        let format = match socket.protocol().and_then(|protocol| protocol.to_str().ok()) {
+            Some(WS_PROTOCOL_PROTOBUF) => Format::Protobuf,
+            _ => Format::Json,
+        };
+
+        Self {
+            socket,
+            format,
+            closed: false,
+            message: PhantomData,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn into_inner(self) -> WebSocket {
+        self.socket
+    }
+
+    pub async fn close(self) -> Result<(), axum::Error> {
+        self.socket.close().await
+    }
+}
+
+impl<T> JsonOrProtobufSocket<T>
+where
+    T: Serialize + ProtobufMessage
+{
+    pub async fn send(&mut self, value: T) -> Result<(), axum::Error> {
+        let (_, encoded) = JsonOrProtobuf::from_format(value, self.format).encode_body();
+        let encoded = encoded?;
+
+        let message = match self.format {
+            Format::Json => Message::Text(String::from_utf8(encoded.to_vec()).map_err(axum::Error::new)?),
+            _ => Message::Binary(encoded.to_vec()),
+        };
+
+        self.socket.send(message).await
+    }
+}
+
+impl<T> JsonOrProtobufSocket<T>
+where
+    T: DeserializeOwned + ProtobufMessage + Default
+{
+    /// Receives the next message, decoding text frames as JSON and binary frames as protobuf.
+    ///
+    /// A frame that fails to decode closes the socket with `1007 Invalid frame payload data` and
+    /// the reason, and is returned as the error. Returns `None` once the socket is closed.
+    pub async fn recv(&mut self) -> Option<Result<JsonOrProtobuf<T>, JsonOrProtobufRejection>> {
+        if self.closed {
+            return None;
+        }
+
+        loop {
+            let decoded = match self.socket.recv().await? {
+                Ok(Message::Text(text)) => JsonCodec::decode(text.into()).map(JsonOrProtobuf::Json),
+                Ok(Message::Binary(binary)) => ProtobufCodec::decode(binary.into()).map(JsonOrProtobuf::Protobuf),
+                Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                Ok(Message::Close(_)) => {
+                    self.closed = true;
+                    return None;
+                },
+                Err(err) => return Some(Err(JsonOrProtobufRejection::BodyReadError(err))),
+            };
+
+            if let Err(rejection) = &decoded {
+                self.closed = true;
+
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::INVALID,
+                    reason: Cow::Owned(close_reason(&rejection.to_string())),
+                }));
+
+                // The peer may already be gone, the decode error is what the caller needs.
+                let _ = self.socket.send(close).await;
+            }
+
+            return Some(decoded);
+        }
+    }
+}
+
+// Close reasons are limited to 123 bytes of UTF-8.
+fn close_reason(reason: &str) -> String {
+    let mut end = reason.len().min(123);
+
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    reason[..end].to_string()
+}